@@ -0,0 +1,226 @@
+use std::{
+    borrow::Borrow, fmt, hash::Hash, marker::PhantomData, sync::Arc,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{CacheMetrics, MeteredCache};
+use crate::{
+    cache::{Cache, ConcurrentCache},
+    metadata::EntryInfo,
+    SharedCache,
+};
+
+/// A [`SharedCache`] which keeps each value in an [`Arc`] and hands out
+/// clones of the Arc rather than of the value, so values don't need to be
+/// Clone and are never copied. Readers share the very same value, and only
+/// hold the lock for as long as it takes to find it.
+///
+/// The cache underneath stores `Arc<V>`, so an `ArcCache<LruCache<C::Key,
+/// Arc<V>>, V>` is the usual way to spell one.
+pub struct ArcCache<C, V>(SharedCache<C>, PhantomData<V>)
+where
+    C: Cache<Value = Arc<V>>;
+
+impl<C, V> ArcCache<C, V>
+where
+    C: Cache<Value = Arc<V>>,
+{
+    /// Wraps a cache into a shared cache accessor, making it safe to move
+    /// across thread boundaries.
+    pub fn with_cache(cache: C) -> Self {
+        Self(SharedCache::with_cache(cache), PhantomData)
+    }
+
+    /// Wraps a cache with gets which only take a shared lock, as with
+    /// [`SharedCache::with_buffered_reads`].
+    pub fn with_buffered_reads(cache: C) -> Self {
+        Self(SharedCache::with_buffered_reads(cache), PhantomData)
+    }
+
+    /// Inserts an item into the cache, returning the item it replaced if
+    /// there was one. Anyone still holding on to the replaced item keeps it
+    /// until they let go.
+    pub fn insert(&self, k: C::Key, v: V) -> Option<Arc<V>> {
+        self.0.insert(k, Arc::new(v))
+    }
+
+    /// Inserts an item which is already in an Arc, so that the cache shares
+    /// it with whoever else has it.
+    pub fn insert_arc(&self, k: C::Key, v: Arc<V>) -> Option<Arc<V>> {
+        self.0.insert(k, v)
+    }
+
+    /// Get an item from the cache. Only the Arc is cloned, not the item.
+    pub fn get<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0.get(k)
+    }
+
+    /// Get an item from the cache, or if it isn't there, make it with `f`
+    /// and insert it, as with [`SharedCache::get_or_insert_with`].
+    pub fn get_or_insert_with<F>(&self, k: C::Key, f: F) -> Arc<V>
+    where
+        F: FnOnce() -> V,
+    {
+        self.0.get_or_insert_with(k, || Arc::new(f()))
+    }
+
+    /// Like [`ArcCache::get_or_insert_with`], but for making values in a
+    /// way that can fail.
+    pub fn try_get_or_insert_with<F, E>(
+        &self,
+        k: C::Key,
+        f: F,
+    ) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        self.0.try_get_or_insert_with(k, || f().map(Arc::new))
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item.
+    pub fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0.entry_info(k)
+    }
+
+    /// Remove an item from the cache, returning the removed item if it existed.
+    pub fn remove<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0.remove(k)
+    }
+
+    /// Clears the cache.
+    pub fn clear(&self) {
+        self.0.clear()
+    }
+
+    /// The number of elements in the cache at present.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the cache is empty at present.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<C, V> ConcurrentCache for ArcCache<C, V>
+where
+    C: Cache<Value = Arc<V>>,
+{
+    type Key = C::Key;
+    type Value = Arc<V>;
+
+    fn insert(&self, k: C::Key, v: Arc<V>) -> Option<Arc<V>> {
+        self.insert_arc(k, v)
+    }
+
+    fn get<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ArcCache::get(self, k)
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ArcCache::entry_info(self, k)
+    }
+
+    fn remove<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ArcCache::remove(self, k)
+    }
+
+    fn clear(&self) {
+        ArcCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        ArcCache::len(self)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<C, V> MeteredCache for ArcCache<C, V>
+where
+    C: Cache<Value = Arc<V>> + MeteredCache,
+{
+    fn metrics(&self) -> CacheMetrics {
+        self.0.metrics()
+    }
+}
+
+impl<C, V> Clone for ArcCache<C, V>
+where
+    C: Cache<Value = Arc<V>>,
+{
+    fn clone(&self) -> Self {
+        ArcCache(self.0.clone(), PhantomData)
+    }
+}
+
+impl<C, V> fmt::Debug for ArcCache<C, V>
+where
+    C: Cache<Value = Arc<V>> + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ArcCache").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{ArcCache, LruCache};
+
+    /// Deliberately not Clone.
+    #[derive(Debug, PartialEq)]
+    struct Document(String);
+
+    #[test]
+    fn test_arc_cache() {
+        let cache: ArcCache<LruCache<usize, Arc<Document>>, Document> =
+            ArcCache::with_cache(LruCache::with_capacity(2));
+        cache.insert(1, Document("one".to_string()));
+
+        let thread_cache = cache.clone();
+        let theirs =
+            thread::spawn(move || thread_cache.get(&1)).join().unwrap();
+        let ours = cache.get(&1).unwrap();
+        assert!(Arc::ptr_eq(&ours, &theirs.unwrap()));
+
+        // whoever has a replaced value keeps it
+        let replaced = cache.insert(1, Document("uno".to_string()));
+        assert!(Arc::ptr_eq(&ours, &replaced.unwrap()));
+        assert_eq!(Document("one".to_string()), *ours);
+        assert_eq!(
+            Some(&Document("uno".to_string())),
+            cache.get(&1).as_deref()
+        );
+
+        let two =
+            cache.get_or_insert_with(2, || Document("two".to_string()));
+        assert!(Arc::ptr_eq(&two, &cache.get(&2).unwrap()));
+    }
+}
@@ -0,0 +1,399 @@
+//! A cache for async code which loads missing values with futures,
+//! without caring which executor those futures run on.
+
+use parking_lot::Mutex;
+use std::{borrow::Borrow, fmt, future::Future, hash::Hash, sync::Arc};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{CacheMetrics, MeteredCache};
+use crate::{
+    cache::{Cache, ConcurrentCache},
+    metadata::EntryInfo,
+    single_flight::{Boarding, Flights},
+};
+
+/// Wrapper for a cache which is shareable across tasks and threads, and
+/// which can load missing values with futures. When several tasks miss the
+/// same key at once, only one of them awaits its loader while the rest wait
+/// for it to finish.
+///
+/// The lock around the cache is only ever held for a moment and never
+/// across an await, so it is fine to use from any executor, or none.
+/// Reloads an ExpiringCache inside sets off are run by its [`Spawner`],
+/// which can hand them to the executor rather than to threads of their
+/// own.
+///
+/// [`Spawner`]: crate::Spawner
+pub struct AsyncCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    cache: Arc<Mutex<C>>,
+    flights: Arc<Flights>,
+}
+
+impl<C> AsyncCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    /// Wraps a cache into an async cache accessor.
+    pub fn with_cache(cache: C) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(cache)),
+            flights: Arc::new(Flights::new()),
+        }
+    }
+
+    /// Inserts an item into the cache.
+    pub fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        self.cache.lock().insert(k, v)
+    }
+
+    /// Get an item from the cache. This clones it to minimize the lock time of
+    /// the cache.
+    pub fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.lock().get(k).cloned()
+    }
+
+    /// Get an item from the cache, or if it isn't there, await `loader` and
+    /// insert what it gives back. When several tasks miss the same key at
+    /// once only the first awaits its loader; the rest wait for it to finish
+    /// and use what it loaded.
+    ///
+    /// If the future loading the value is dropped before it finishes, one
+    /// of the waiting tasks awaits its own loader instead.
+    pub async fn get_with<F>(&self, k: C::Key, loader: F) -> C::Value
+    where
+        F: Future<Output = C::Value>,
+    {
+        let loaded = self
+            .try_get_with(k, async {
+                Ok::<C::Value, std::convert::Infallible>(loader.await)
+            })
+            .await;
+
+        match loaded {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like [`AsyncCache::get_with`], but for loaders which can fail. The
+    /// error goes back to the task whose loader failed and nothing is
+    /// inserted. Tasks which were waiting on it await their own loaders in
+    /// turn, one at a time, rather than all receiving the same error.
+    pub async fn try_get_with<F, E>(
+        &self,
+        k: C::Key,
+        loader: F,
+    ) -> Result<C::Value, E>
+    where
+        F: Future<Output = Result<C::Value, E>>,
+    {
+        if let Some(v) = self.get(&k) {
+            return Ok(v);
+        }
+
+        // only the first look counts towards the stats, as whatever
+        // happens next this was a miss
+        loop {
+            match self.flights.board(&k) {
+                Boarding::Leader(_flight) => {
+                    // someone may have finished loading it in between
+                    // missing it and boarding
+                    if let Some(v) = self.lookup(&k) {
+                        return Ok(v);
+                    }
+
+                    let v = loader.await?;
+                    self.insert(k, v.clone());
+                    return Ok(v);
+                }
+                Boarding::Follower(landing) => {
+                    landing.await;
+                    if let Some(v) = self.lookup(&k) {
+                        return Ok(v);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item.
+    pub fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.lock().entry_info(k)
+    }
+
+    /// Remove an item from the cache, returning the removed item if it existed.
+    pub fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.lock().remove(k)
+    }
+
+    /// Clears the cache.
+    pub fn clear(&self) {
+        self.cache.lock().clear()
+    }
+
+    /// The number of elements in the cache at present.
+    pub fn len(&self) -> usize {
+        self.cache.lock().len()
+    }
+
+    /// Whether the cache is empty at present.
+    pub fn is_empty(&self) -> bool {
+        self.cache.lock().is_empty()
+    }
+
+    /// Looks an item up without it counting as a get or setting off any
+    /// reloads, for caches which can peek. Those which can't are gotten
+    /// from as usual.
+    fn lookup<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut cache = self.cache.lock();
+        match cache.can_peek() {
+            true => cache.peek(k).cloned(),
+            false => cache.get(k).cloned(),
+        }
+    }
+}
+
+impl<C> ConcurrentCache for AsyncCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    type Key = C::Key;
+    type Value = C::Value;
+
+    fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        AsyncCache::insert(self, k, v)
+    }
+
+    fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        AsyncCache::get(self, k)
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        AsyncCache::entry_info(self, k)
+    }
+
+    fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        AsyncCache::remove(self, k)
+    }
+
+    fn clear(&self) {
+        AsyncCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        AsyncCache::len(self)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<C> MeteredCache for AsyncCache<C>
+where
+    C: Cache + MeteredCache,
+    C::Value: Clone,
+{
+    fn metrics(&self) -> CacheMetrics {
+        self.cache.lock().metrics()
+    }
+}
+
+impl<C> Clone for AsyncCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        AsyncCache {
+            cache: self.cache.clone(),
+            flights: self.flights.clone(),
+        }
+    }
+}
+
+impl<C> fmt::Debug for AsyncCache<C>
+where
+    C: Cache + fmt::Debug,
+    C::Value: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("AsyncCache");
+        match self.cache.try_lock() {
+            Some(cache) => d.field("cache", &*cache),
+            None => d.field("cache", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{self, Future},
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+        time::Duration,
+    };
+
+    use crate::{AsyncCache, LruCache};
+
+    /// Wakes a thread which is blocked on a future.
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// About the smallest executor there is: polls one future on the
+    /// current thread, parking in between.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = pin!(f);
+        let waker =
+            Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                return v;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_get_with() {
+        let cache: AsyncCache<LruCache<usize, usize>> =
+            AsyncCache::with_cache(LruCache::with_capacity(10));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(4));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let loads = loads.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    block_on(cache.get_with(1, async {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        42
+                    }))
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(42, thread.join().unwrap());
+        }
+        assert_eq!(1, loads.load(Ordering::SeqCst));
+        assert_eq!(Some(42), cache.get(&1));
+    }
+
+    #[test]
+    fn test_dropped_loader() {
+        let cache: AsyncCache<LruCache<usize, usize>> =
+            AsyncCache::with_cache(LruCache::with_capacity(10));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // start a load which never finishes, and someone waiting on it
+        let mut stuck =
+            Box::pin(cache.get_with(1, std::future::pending()));
+        assert!(stuck.as_mut().poll(&mut cx).is_pending());
+        let mut waiting = Box::pin(cache.get_with(1, async { 7 }));
+        assert!(waiting.as_mut().poll(&mut cx).is_pending());
+
+        // giving up on the first load hands it to the one waiting
+        drop(stuck);
+        assert_eq!(Poll::Ready(7), waiting.as_mut().poll(&mut cx));
+    }
+
+    #[test]
+    fn test_try_get_with() {
+        let cache: AsyncCache<LruCache<usize, usize>> =
+            AsyncCache::with_cache(LruCache::with_capacity(10));
+
+        assert_eq!(
+            Err("failed to load"),
+            block_on(
+                cache.try_get_with(1, async { Err("failed to load") })
+            )
+        );
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(
+            Ok(1),
+            block_on(cache.try_get_with(1, async { Ok::<_, ()>(1) }))
+        );
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut lru = LruCache::with_capacity(10);
+        lru.set_record_stats(true);
+        let cache: AsyncCache<LruCache<usize, usize>> =
+            AsyncCache::with_cache(lru);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let loaded = AtomicBool::new(false);
+        let mut loading = Box::pin(cache.get_with(
+            1,
+            future::poll_fn(|_| match loaded.load(Ordering::SeqCst) {
+                true => Poll::Ready(1),
+                false => Poll::Pending,
+            }),
+        ));
+        assert!(loading.as_mut().poll(&mut cx).is_pending());
+        let mut waiting = Box::pin(cache.get_with(1, async { 2 }));
+        assert!(waiting.as_mut().poll(&mut cx).is_pending());
+
+        loaded.store(true, Ordering::SeqCst);
+        assert_eq!(Poll::Ready(1), loading.as_mut().poll(&mut cx));
+        assert_eq!(Poll::Ready(1), waiting.as_mut().poll(&mut cx));
+
+        // each only missed the once, however many times it looked
+        let stats = cache.cache.lock().stats().unwrap();
+        assert_eq!(0, stats.hits);
+        assert_eq!(2, stats.misses);
+    }
+}
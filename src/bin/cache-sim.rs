@@ -0,0 +1,557 @@
+//! Replays access traces against the caches in this crate at several
+//! capacities, to help pick a policy and a size from production logs
+//! without having to try them out in production.
+//!
+//! ```text
+//! cache-sim [OPTIONS] TRACE
+//!
+//!     --format plain|csv|arc|lirs  how the trace is written [plain]
+//!     --capacities LIST            capacities to try, in items or as a
+//!                                  percentage of the distinct keys in the
+//!                                  trace [1%,5%,10%,25%,50%]
+//!     --policies LIST              policies to try [every one]
+//!     --ttl SECONDS                timeout for the expiring policy [3600]
+//!     --json                       print JSON rather than a table
+//! ```
+//!
+//! A TRACE of `-` is read from stdin.
+//!
+//! Traces carry no timestamps and are replayed as fast as they can be
+//! read, so the expiring policy's timeout is measured against the clock
+//! on the wall rather than the trace, and rarely comes into play.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    process,
+    time::Duration,
+};
+
+use mysterious_cache::{Cache, ExpiringCache, LruCache};
+
+/// How many bytes are in each block of the ARC and LIRS traces, which
+/// count blocks rather than bytes.
+const BLOCK_SIZE: u64 = 512;
+
+const USAGE: &str = "usage: cache-sim [--format plain|csv|arc|lirs] \
+                     [--capacities LIST] [--policies LIST] \
+                     [--ttl SECONDS] [--json] TRACE";
+
+/// How a trace is written down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// One key per line.
+    Plain,
+    /// A key and its size in bytes per line, separated by a comma. Any
+    /// further columns are ignored, as is a header.
+    Csv,
+    /// The format of the traces from the ARC paper: a starting block, a
+    /// number of blocks, and two columns which are ignored.
+    Arc,
+    /// The format of the traces from the LIRS paper: one block per line,
+    /// with a `*` marking the end.
+    Lirs,
+}
+
+/// A single request in a trace. Keys are numbered in the order they first
+/// appear, which keeps the trace compact however long its keys are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Request {
+    key: u64,
+    size: u64,
+}
+
+struct Trace {
+    requests: Vec<Request>,
+    distinct_keys: usize,
+}
+
+/// A capacity to try, either outright or relative to the trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Capacity {
+    Items(usize),
+    Percent(f64),
+}
+
+struct Options {
+    format: Format,
+    capacities: Vec<Capacity>,
+    policies: Vec<&'static Policy>,
+    ttl: Duration,
+    json: bool,
+    trace: String,
+}
+
+/// A cache policy the simulator knows how to build and replay a trace
+/// against.
+struct Policy {
+    name: &'static str,
+    run: fn(&[Request], usize, &Options) -> Outcome,
+}
+
+/// Every policy the simulator can try. Anything which implements
+/// [`Cache`] can be added here with a name and a way to build it.
+const POLICIES: &[Policy] = &[
+    Policy {
+        name: "lru",
+        run: |requests, capacity, _| {
+            replay(LruCache::with_capacity(capacity), requests)
+        },
+    },
+    Policy {
+        name: "expiring",
+        run: |requests, capacity, options| {
+            replay(
+                ExpiringCache::with_capacity_and_timeout(
+                    capacity,
+                    options.ttl,
+                ),
+                requests,
+            )
+        },
+    },
+];
+
+/// How a policy did at one capacity.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Outcome {
+    requests: u64,
+    hits: u64,
+    bytes: u64,
+    hit_bytes: u64,
+}
+
+impl Outcome {
+    fn hit_ratio(&self) -> f64 {
+        ratio(self.hits, self.requests)
+    }
+
+    fn byte_hit_ratio(&self) -> f64 {
+        ratio(self.hit_bytes, self.bytes)
+    }
+}
+
+fn ratio(n: u64, d: u64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        n as f64 / d as f64
+    }
+}
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1)) {
+        eprintln!("cache-sim: {}", e);
+        process::exit(2);
+    }
+}
+
+fn run<I>(args: I) -> Result<(), String>
+where
+    I: Iterator<Item = String>,
+{
+    let options = parse_args(args)?;
+
+    let trace = if options.trace == "-" {
+        read_trace(io::stdin().lock(), options.format)?
+    } else {
+        let file = File::open(&options.trace)
+            .map_err(|e| format!("{}: {}", options.trace, e))?;
+        read_trace(BufReader::new(file), options.format)?
+    };
+
+    let results = simulate(&trace, &options);
+
+    if options.json {
+        print!("{}", render_json(&trace, &results));
+    } else {
+        print!("{}", render_table(&results));
+    }
+
+    Ok(())
+}
+
+fn parse_args<I>(mut args: I) -> Result<Options, String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut options = Options {
+        format: Format::Plain,
+        capacities: parse_capacities("1%,5%,10%,25%,50%")?,
+        policies: POLICIES.iter().collect(),
+        ttl: Duration::from_secs(3600),
+        json: false,
+        trace: String::new(),
+    };
+    let mut trace = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("{} needs a value", name))
+        };
+
+        match arg.as_str() {
+            "--format" => {
+                options.format = match value("--format")?.as_str() {
+                    "plain" => Format::Plain,
+                    "csv" => Format::Csv,
+                    "arc" => Format::Arc,
+                    "lirs" => Format::Lirs,
+                    other => {
+                        return Err(format!("unknown format {}", other))
+                    }
+                }
+            }
+            "--capacities" => {
+                options.capacities =
+                    parse_capacities(&value("--capacities")?)?
+            }
+            "--policies" => {
+                options.policies = value("--policies")?
+                    .split(',')
+                    .map(|name| {
+                        POLICIES
+                            .iter()
+                            .find(|p| p.name == name)
+                            .ok_or_else(|| {
+                                format!("unknown policy {}", name)
+                            })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "--ttl" => {
+                let secs = value("--ttl")?;
+                options.ttl = secs
+                    .parse()
+                    .ok()
+                    .and_then(|secs| {
+                        Duration::try_from_secs_f64(secs).ok()
+                    })
+                    .ok_or_else(|| format!("bad ttl {}", secs))?
+            }
+            "--json" => options.json = true,
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if trace.is_none() => trace = Some(arg),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+
+    options.trace = trace.ok_or_else(|| USAGE.to_owned())?;
+
+    Ok(options)
+}
+
+fn parse_capacities(list: &str) -> Result<Vec<Capacity>, String> {
+    list.split(',')
+        .map(|c| {
+            let bad = || format!("bad capacity {}", c);
+
+            match c.strip_suffix('%') {
+                Some(percent) => percent
+                    .parse()
+                    .ok()
+                    .filter(|p| *p > 0.0)
+                    .map(Capacity::Percent)
+                    .ok_or_else(bad),
+                None => c
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .map(Capacity::Items)
+                    .ok_or_else(bad),
+            }
+        })
+        .collect()
+}
+
+fn read_trace<R>(reader: R, format: Format) -> Result<Trace, String>
+where
+    R: BufRead,
+{
+    let mut ids: HashMap<String, u64> = HashMap::new();
+    let mut requests = Vec::new();
+
+    let mut request = |key: &str, size: u64| {
+        let next = ids.len() as u64;
+        let key = *ids.entry(key.to_owned()).or_insert(next);
+        requests.push(Request { key, size });
+    };
+
+    for (n, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        let bad = || format!("line {}: can't read {:?}", n + 1, line);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match format {
+            Format::Plain => request(line, 1),
+            Format::Csv => {
+                let mut columns = line.split(',').map(str::trim);
+                let key = columns.next().ok_or_else(bad)?;
+                match columns.next().map(str::parse) {
+                    Some(Ok(size)) => request(key, size),
+                    // the first line may well be a header
+                    Some(Err(_)) if n == 0 => {}
+                    _ => return Err(bad()),
+                }
+            }
+            Format::Arc => {
+                let mut columns = line.split_whitespace();
+                let mut number =
+                    || -> Option<u64> { columns.next()?.parse().ok() };
+                let (start, count) = match (number(), number()) {
+                    (Some(start), Some(count)) => (start, count),
+                    _ => return Err(bad()),
+                };
+                for block in start..start.saturating_add(count) {
+                    request(&block.to_string(), BLOCK_SIZE);
+                }
+            }
+            Format::Lirs => {
+                if line == "*" {
+                    break;
+                }
+                line.parse::<u64>().map_err(|_| bad())?;
+                request(line, BLOCK_SIZE);
+            }
+        }
+    }
+
+    Ok(Trace {
+        requests,
+        distinct_keys: ids.len(),
+    })
+}
+
+/// Plays every request against a cache, counting a request which finds
+/// its key as a hit and inserting the key for one which doesn't.
+fn replay<C>(mut cache: C, requests: &[Request]) -> Outcome
+where
+    C: Cache<Key = u64, Value = u64>,
+{
+    let mut outcome = Outcome::default();
+
+    for request in requests {
+        outcome.requests += 1;
+        outcome.bytes += request.size;
+
+        if cache.get(&request.key).is_some() {
+            outcome.hits += 1;
+            outcome.hit_bytes += request.size;
+        } else {
+            cache.insert(request.key, request.size);
+        }
+    }
+
+    outcome
+}
+
+fn simulate(
+    trace: &Trace,
+    options: &Options,
+) -> Vec<(&'static str, usize, Outcome)> {
+    let mut results = Vec::new();
+
+    // small traces can round several percentages to the same capacity,
+    // which only needs trying once
+    let mut capacities: Vec<usize> = Vec::new();
+    for capacity in &options.capacities {
+        let capacity = match *capacity {
+            Capacity::Items(n) => n,
+            Capacity::Percent(p) => ((trace.distinct_keys as f64 * p
+                / 100.0)
+                .round() as usize)
+                .max(1),
+        };
+        if !capacities.contains(&capacity) {
+            capacities.push(capacity);
+        }
+    }
+
+    for policy in &options.policies {
+        for &capacity in &capacities {
+            let outcome =
+                (policy.run)(&trace.requests, capacity, options);
+            results.push((policy.name, capacity, outcome));
+        }
+    }
+
+    results
+}
+
+fn render_table(results: &[(&str, usize, Outcome)]) -> String {
+    let mut out = format!(
+        "{:<10} {:>12} {:>12} {:>10} {:>15}\n",
+        "policy", "capacity", "requests", "hit ratio", "byte hit ratio"
+    );
+
+    for (policy, capacity, outcome) in results {
+        out.push_str(&format!(
+            "{:<10} {:>12} {:>12} {:>10.4} {:>15.4}\n",
+            policy,
+            capacity,
+            outcome.requests,
+            outcome.hit_ratio(),
+            outcome.byte_hit_ratio()
+        ));
+    }
+
+    out
+}
+
+fn render_json(
+    trace: &Trace,
+    results: &[(&str, usize, Outcome)],
+) -> String {
+    // policy names are all plain words, so nothing here needs escaping
+    let results: Vec<String> = results
+        .iter()
+        .map(|(policy, capacity, outcome)| {
+            format!(
+                "{{\"policy\":\"{}\",\"capacity\":{},\"hits\":{},\
+                 \"hit_ratio\":{},\"hit_bytes\":{},\"byte_hit_ratio\":{}}}",
+                policy,
+                capacity,
+                outcome.hits,
+                outcome.hit_ratio(),
+                outcome.hit_bytes,
+                outcome.byte_hit_ratio()
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"requests\":{},\"distinct_keys\":{},\"bytes\":{},\
+         \"results\":[{}]}}\n",
+        trace.requests.len(),
+        trace.distinct_keys,
+        trace.requests.iter().map(|r| r.size).sum::<u64>(),
+        results.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mysterious_cache::LruCache;
+
+    use super::{
+        parse_args, parse_capacities, read_trace, render_json, replay,
+        simulate, Capacity, Format, Request, BLOCK_SIZE,
+    };
+
+    #[test]
+    fn test_read_trace() {
+        let plain = read_trace(
+            "a\nb\n\n# comment\na\n".as_bytes(),
+            Format::Plain,
+        )
+        .unwrap();
+        assert_eq!(3, plain.requests.len());
+        assert_eq!(2, plain.distinct_keys);
+        assert_eq!(plain.requests[0], plain.requests[2]);
+
+        let csv = read_trace(
+            "key,size\na,100\nb,20,extra\n".as_bytes(),
+            Format::Csv,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Request { key: 0, size: 100 },
+                Request { key: 1, size: 20 }
+            ],
+            csv.requests
+        );
+        assert!(read_trace("a,1\nb\n".as_bytes(), Format::Csv).is_err());
+
+        let arc =
+            read_trace("10 3 0 1\n11 1 0 2\n".as_bytes(), Format::Arc)
+                .unwrap();
+        assert_eq!(4, arc.requests.len());
+        assert_eq!(3, arc.distinct_keys);
+        assert_eq!(BLOCK_SIZE, arc.requests[0].size);
+
+        let lirs =
+            read_trace("5\n6\n5\n*\n7\n".as_bytes(), Format::Lirs)
+                .unwrap();
+        assert_eq!(3, lirs.requests.len());
+    }
+
+    #[test]
+    fn test_replay() {
+        let small = Request { key: 0, size: 1 };
+        let big = Request { key: 1, size: 9 };
+        let outcome = replay(
+            LruCache::with_capacity(1),
+            &[small, small, big, small, big, big],
+        );
+
+        assert_eq!(6, outcome.requests);
+        assert_eq!(2, outcome.hits);
+        assert_eq!(10, outcome.hit_bytes);
+        assert_eq!(30, outcome.bytes);
+    }
+
+    #[test]
+    fn test_simulate() {
+        let args = [
+            "--capacities",
+            "1,50%",
+            "--policies",
+            "lru,expiring",
+            "--ttl",
+            "60",
+            "--json",
+            "trace.txt",
+        ];
+        let options =
+            parse_args(args.iter().map(|a| a.to_string())).unwrap();
+        assert_eq!(Duration::from_secs(60), options.ttl);
+        assert!(options.json);
+        assert_eq!("trace.txt", options.trace);
+        assert!(parse_capacities("0").is_err());
+        assert_eq!(
+            vec![Capacity::Items(1), Capacity::Percent(50.0)],
+            options.capacities
+        );
+
+        let trace =
+            read_trace("a\nb\nc\nd\na\nb\n".as_bytes(), Format::Plain)
+                .unwrap();
+        let results = simulate(&trace, &options);
+        let capacities: Vec<_> =
+            results.iter().map(|(p, c, _)| (*p, *c)).collect();
+        assert_eq!(
+            vec![
+                ("lru", 1),
+                ("lru", 2),
+                ("expiring", 1),
+                ("expiring", 2)
+            ],
+            capacities
+        );
+
+        assert!(render_json(&trace, &results).starts_with(
+            "{\"requests\":6,\"distinct_keys\":4,\"bytes\":6,\"results\":[\
+             {\"policy\":\"lru\",\"capacity\":1,\"hits\":0,"
+        ));
+    }
+
+    #[test]
+    fn test_bad_ttl() {
+        for ttl in ["-1", "nan", "inf", "soon"] {
+            let args = ["--ttl", ttl, "trace.txt"];
+            assert_eq!(
+                Some(format!("bad ttl {}", ttl)),
+                parse_args(args.iter().map(|a| a.to_string())).err()
+            );
+        }
+    }
+}
@@ -1,41 +1,175 @@
-use std::hash::Hash;
+use std::{
+    borrow::Borrow,
+    collections::hash_map::DefaultHasher,
+    hash::{BuildHasher, BuildHasherDefault, Hash},
+};
+
+use crate::{metadata::EntryInfo, stats::CacheStats};
+
+/// What a cache hashes a key down to. Caches in this crate identify their
+/// entries by this rather than by holding on to the keys themselves.
+pub type KeyHash = u64;
 
 /// Describes what a cache is.
-pub trait Cache<K, V>
-where
-    K: Eq + Hash,
-{
+pub trait Cache {
+    /// What the cache's items are found by.
+    type Key: Eq + Hash;
+
+    /// What the cache holds.
+    type Value;
+
     /// Push a new element into the Cache, which may evict the oldest item if
     /// the cache is at capacity. Returns the previous value in the cache if the
     /// key already had a value there.
-    fn insert(&mut self, k: K, v: V) -> Option<V>;
+    fn insert(
+        &mut self,
+        k: Self::Key,
+        v: Self::Value,
+    ) -> Option<Self::Value>;
 
     /// Get an item from the Cache. This also makes the item the youngest item
     /// in the cache and the least eligible for eviction.
-    fn get<'a, Q>(&'a mut self, k: &Q) -> Option<&'a V>
+    fn get<Q>(&mut self, k: &Q) -> Option<&Self::Value>
     where
-        Q: Hash + Eq,
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
         self.get_mut(k).map(|v| {
-            let v: &V = v;
+            let v: &Self::Value = v;
             v
         })
     }
 
     /// Get a mutable reference to an item from the cache. This also makes the
     /// item the youngest item in the cache and the least elegible for eviction.
-    fn get_mut<'a, Q>(&'a mut self, k: &Q) -> Option<&'a mut V>
+    fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut Self::Value>
     where
-        Q: Hash + Eq;
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Get an item from the cache without making it any younger, so that it
+    /// can be done without exclusive access to the cache. Caches which
+    /// can't do that find nothing, and say so through
+    /// [`Cache::can_peek`].
+    fn peek<Q>(&self, _k: &Q) -> Option<&Self::Value>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        None
+    }
+
+    /// Whether [`Cache::peek`] actually looks items up. Caches which
+    /// implement it, and [`Cache::touch_hash`] along with it, should say so
+    /// here, as a SharedCache with buffered reads only reads them under a
+    /// shared lock when they do.
+    fn can_peek(&self) -> bool {
+        false
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item. Caches which don't keep
+    /// any metadata only know that the item is there.
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.peek(k).map(|_| EntryInfo::bare())
+    }
+
+    /// Hashes a key the same way the cache does internally, which is how
+    /// [`Cache::touch_hash`] finds its item. Caches which don't hash their
+    /// keys get a fixed hash which is the same for every cache.
+    fn key_hash<Q>(&self, k: &Q) -> KeyHash
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        BuildHasherDefault::<DefaultHasher>::default().hash_one(k)
+    }
+
+    /// Treats the item whose key hashes to `hash_k` as though it had been
+    /// gotten, making it the youngest item in the cache. Does nothing if
+    /// there is no such item, or if the cache doesn't implement it.
+    fn touch_hash(&mut self, _hash_k: KeyHash) {}
 
     /// Bust a move, returning whatever was there.
-    fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    fn remove<Q>(&mut self, k: &Q) -> Option<Self::Value>
     where
-        Q: Hash + Eq;
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
 
     /// Clears the cache entirely.
     fn clear(&mut self);
 
     /// The number of items stored in the cache right now.
     fn len(&self) -> usize;
+
+    /// Whether the cache has nothing in it right now.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Starts or stops keeping [`CacheStats`], for caches which keep
+    /// them. Caches which don't ignore this.
+    fn set_record_stats(&mut self, _record: bool) {}
+
+    /// What the cache has been up to since it started recording stats, or
+    /// None if it isn't recording them or doesn't keep any.
+    fn stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// Sets every stat back to zero.
+    fn reset_stats(&mut self) {}
+}
+
+/// Describes a cache which can be used from many threads at once through
+/// a shared reference, handing out clones of its values rather than
+/// references into it.
+pub trait ConcurrentCache {
+    /// What the cache's items are found by.
+    type Key: Eq + Hash;
+
+    /// What the cache holds.
+    type Value;
+
+    /// Push a new element into the cache, returning the previous value
+    /// for the key if there was one.
+    fn insert(
+        &self,
+        k: Self::Key,
+        v: Self::Value,
+    ) -> Option<Self::Value>;
+
+    /// Get a copy of an item from the cache.
+    fn get<Q>(&self, k: &Q) -> Option<Self::Value>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item.
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Remove an item from the cache, returning it if it was there.
+    fn remove<Q>(&self, k: &Q) -> Option<Self::Value>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Clears the cache entirely.
+    fn clear(&self);
+
+    /// The number of items stored in the cache right now.
+    fn len(&self) -> usize;
+
+    /// Whether the cache has nothing in it right now.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
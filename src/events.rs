@@ -0,0 +1,290 @@
+//! Lets callers watch what a cache is doing as it does it, for debugging
+//! or keeping an eye on it.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::cache::KeyHash;
+
+/// Something which happened to an item in a cache. Caches don't hold on to
+/// their keys, so items are named by the hash of their key, which is the
+/// same hash [`Cache::key_hash`] gives back. Events carry neither keys nor
+/// values, so they can't be used to rebuild a cache somewhere else.
+///
+/// [`Cache::key_hash`]: crate::Cache::key_hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheEvent {
+    /// An item was inserted under a key which wasn't in the cache.
+    Inserted(KeyHash),
+    /// An item was inserted over one which was already in the cache.
+    Updated(KeyHash),
+    /// An item was read, and so became the youngest in the cache.
+    Accessed(KeyHash),
+    /// An item was thrown out to make room for another. One which had
+    /// expired by then is reported as expired instead.
+    Evicted(KeyHash),
+    /// An item was found to have expired and was thrown out.
+    Expired(KeyHash),
+    /// An item was removed by hand.
+    Removed(KeyHash),
+    /// Everything was removed from the cache at once.
+    Cleared,
+}
+
+/// What to do with a new event when the buffer of events waiting to be
+/// received is full. Either way the cache carries on without waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Forget the new event, keeping the ones already waiting.
+    DropNewest,
+    /// Forget the oldest event waiting, making room for the new one.
+    DropOldest,
+}
+
+/// A cache which can be subscribed to for [`CacheEvent`]s.
+pub trait EventSource {
+    /// Starts sending events to a new subscriber, buffering at most
+    /// `capacity` of them while they wait to be received. Panics if the
+    /// capacity is zero.
+    fn subscribe(
+        &mut self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> CacheEvents;
+}
+
+/// The events waiting for a subscriber.
+struct Queue {
+    events: VecDeque<CacheEvent>,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: u64,
+    /// Whether the cache is still around to send anything.
+    open: bool,
+    /// Whether the subscriber is still around to receive anything.
+    subscribed: bool,
+}
+
+struct Channel {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // nothing panics while holding the lock, so there is nothing to be
+        // worried about if it is poisoned anyway
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The receiving end of a subscription to a cache's events.
+///
+/// Iterating over it blocks waiting for each event, and finishes once the
+/// cache is dropped and every event it sent has been received.
+pub struct CacheEvents {
+    channel: Arc<Channel>,
+}
+
+impl CacheEvents {
+    /// Takes the oldest event waiting, if there is one, without waiting
+    /// for one to arrive.
+    pub fn try_recv(&self) -> Option<CacheEvent> {
+        self.channel.lock().events.pop_front()
+    }
+
+    /// Takes the oldest event waiting, waiting for one to arrive if there
+    /// aren't any. Gives back None once the cache has been dropped and
+    /// there is nothing left to receive.
+    pub fn recv(&self) -> Option<CacheEvent> {
+        let mut queue = self.channel.lock();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Some(event);
+            }
+            if !queue.open {
+                return None;
+            }
+            queue = self
+                .channel
+                .ready
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Like [`CacheEvents::recv`], but gives up and returns None if no
+    /// event arrives within `timeout`.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Option<CacheEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.channel.lock();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if !queue.open || now >= deadline {
+                return None;
+            }
+            queue = self
+                .channel
+                .ready
+                .wait_timeout(queue, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// How many events have been thrown away so far because the buffer
+    /// was full.
+    pub fn dropped(&self) -> u64 {
+        self.channel.lock().dropped
+    }
+}
+
+impl Iterator for CacheEvents {
+    type Item = CacheEvent;
+
+    fn next(&mut self) -> Option<CacheEvent> {
+        self.recv()
+    }
+}
+
+impl Drop for CacheEvents {
+    fn drop(&mut self) {
+        self.channel.lock().subscribed = false;
+    }
+}
+
+/// The sending end of every subscription to a cache. Sending costs next to
+/// nothing when nobody is subscribed.
+#[derive(Default)]
+pub(crate) struct EventSink {
+    channels: Vec<Arc<Channel>>,
+}
+
+impl EventSink {
+    pub(crate) fn subscribe(
+        &mut self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> CacheEvents {
+        assert!(
+            capacity > 0,
+            "an event buffer needs room for one event"
+        );
+
+        self.channels.retain(|c| c.lock().subscribed);
+
+        let channel = Arc::new(Channel {
+            queue: Mutex::new(Queue {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                overflow,
+                dropped: 0,
+                open: true,
+                subscribed: true,
+            }),
+            ready: Condvar::new(),
+        });
+        self.channels.push(channel.clone());
+
+        CacheEvents { channel }
+    }
+
+    /// Hands an event to every subscriber, never waiting on any of them.
+    pub(crate) fn emit(&self, event: CacheEvent) {
+        for channel in &self.channels {
+            let mut queue = channel.lock();
+
+            if !queue.subscribed {
+                continue;
+            }
+
+            if queue.events.len() >= queue.capacity {
+                queue.dropped += 1;
+                match queue.overflow {
+                    Overflow::DropNewest => continue,
+                    Overflow::DropOldest => {
+                        queue.events.pop_front();
+                    }
+                }
+            }
+
+            queue.events.push_back(event);
+            drop(queue);
+            channel.ready.notify_one();
+        }
+    }
+}
+
+impl Drop for EventSink {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            channel.lock().open = false;
+            channel.ready.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::{CacheEvent, EventSink, Overflow};
+
+    #[test]
+    fn test_overflow() {
+        let mut sink = EventSink::default();
+        let newest = sink.subscribe(2, Overflow::DropNewest);
+        let oldest = sink.subscribe(2, Overflow::DropOldest);
+
+        for hash_k in 0..4 {
+            sink.emit(CacheEvent::Inserted(hash_k));
+        }
+
+        assert_eq!(2, newest.dropped());
+        assert_eq!(Some(CacheEvent::Inserted(0)), newest.try_recv());
+        assert_eq!(Some(CacheEvent::Inserted(1)), newest.try_recv());
+        assert_eq!(None, newest.try_recv());
+
+        assert_eq!(2, oldest.dropped());
+        assert_eq!(Some(CacheEvent::Inserted(2)), oldest.try_recv());
+        assert_eq!(Some(CacheEvent::Inserted(3)), oldest.try_recv());
+        assert_eq!(
+            None,
+            oldest.recv_timeout(Duration::from_millis(10))
+        );
+
+        // a subscriber which has gone away doesn't hold anything up
+        drop(newest);
+        sink.emit(CacheEvent::Cleared);
+        assert_eq!(Some(CacheEvent::Cleared), oldest.try_recv());
+    }
+
+    #[test]
+    fn test_recv_ends_with_the_cache() {
+        let mut sink = EventSink::default();
+        let events = sink.subscribe(16, Overflow::DropNewest);
+
+        let consumer =
+            thread::spawn(move || events.collect::<Vec<_>>());
+
+        sink.emit(CacheEvent::Removed(7));
+        sink.emit(CacheEvent::Cleared);
+        drop(sink);
+
+        assert_eq!(
+            vec![CacheEvent::Removed(7), CacheEvent::Cleared],
+            consumer.join().unwrap()
+        );
+    }
+}
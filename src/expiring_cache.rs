@@ -2,18 +2,241 @@
 //! old such that they will not be returned by get anymore.
 
 use std::{
+    borrow::Borrow,
     collections::hash_map::RandomState,
+    error::Error,
+    fmt,
     hash::{BuildHasher, Hash},
-    time::{Duration, Instant},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::cache::Cache;
+use crate::cache::{Cache, KeyHash};
+use crate::events::{
+    CacheEvent, CacheEvents, EventSink, EventSource, Overflow,
+};
+use crate::expiry::Expiry;
+use crate::loader::{Loader, Reload, Reloaded, Spawner, ThreadSpawner};
 use crate::lru_cache::LruCache;
+use crate::metadata::EntryInfo;
+use crate::rng::Rng;
+use crate::stats::{CacheStats, StatsRecorder};
+#[cfg(feature = "tracing")]
+use crate::trace::{CacheTracer, EvictionCause, Traceable};
+
+/// When an entry stops being returned from the cache.
+#[derive(Clone, Copy)]
+enum Expiration {
+    /// Once the entry is older than the timeout of the cache.
+    Timeout,
+    /// At a point in time decided by an [`Expiry`] policy.
+    At(Instant),
+    /// At a wall-clock time given by the caller, along with the Instant it
+    /// was reckoned to be when the entry was inserted. Whichever of the two
+    /// clocks gets there first expires the entry, so the wall clock being
+    /// set backwards cannot keep an entry around for longer than it was
+    /// meant to live, and being set forwards expires it on time.
+    Wall { at: SystemTime, instant: Instant },
+    /// Not ever.
+    Never,
+}
+
+/// A moment by both clocks, read once and handed around so that
+/// everything worked out from it agrees on what time it is.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Now {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl Now {
+    pub(crate) fn new() -> Self {
+        Now {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+}
+
+impl Expiration {
+    /// Turns a lifetime handed back by an [`Expiry`] into a deadline.
+    fn after(now: Instant, lifetime: Option<Duration>) -> Self {
+        match lifetime.and_then(|lifetime| now.checked_add(lifetime)) {
+            Some(deadline) => Expiration::At(deadline),
+            None => Expiration::Never,
+        }
+    }
+
+    /// Turns a wall-clock deadline into an expiration as of `now`.
+    fn wall(at: SystemTime, now: Now) -> Self {
+        let instant = match at.duration_since(now.wall) {
+            Ok(left) => now.instant.checked_add(left),
+            // already passed, which the wall clock check will catch
+            Err(_) => Some(now.instant),
+        };
+
+        match instant {
+            Some(instant) => Expiration::Wall { at, instant },
+            None => Expiration::Never,
+        }
+    }
+}
+
+/// An entry taken out of an [`ExpiringCache`] by
+/// [`ExpiringCache::snapshot`], which can be put back into another cache,
+/// perhaps in another process, with [`ExpiringCache::restore`]. The
+/// expiration is kept as a wall-clock time since an Instant means nothing
+/// outside of the process that made it. With the `serde` feature it can be
+/// serialized, to be saved or sent.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct SnapshotEntry<K, V> {
+    pub key: K,
+    pub value: V,
+    /// When the entry expires, or None if it never does.
+    pub expires_at: Option<SystemTime>,
+}
 
-/// Wraps a value with the Instant it was inserted at.
-struct ExpiringEntry<V> {
+/// Wraps a value with its key, the Instant it was inserted at, and when
+/// it expires. The key is kept so that [`Expiry`] policies and loaders
+/// can look at it, and so that snapshots and dumps can name it.
+struct ExpiringEntry<K, V> {
+    key: K,
     value: V,
     inserted_at: Instant,
+    expiration: Expiration,
+    /// Taken off of the deadline so that entries inserted together do
+    /// not all expire together.
+    jitter: Duration,
+    /// How long it took to compute the value, which decides how early
+    /// a refresh is recommended.
+    recompute: Duration,
+    /// When the value was last written or a reload of it was attempted,
+    /// which is when the refresh interval counts from.
+    refreshed_at: Instant,
+    /// Which write to the cache put the entry there, so a reload can tell
+    /// whether the entry it loaded for is still the one in the cache.
+    written: u64,
+    /// Whether a reload of the entry has been handed out and not yet put
+    /// back, so that no more are started in the meantime.
+    reloading: bool,
+}
+
+impl<K, V> ExpiringEntry<K, V> {
+    /// An entry written at `now`, with nothing taken off of its deadline.
+    fn new(
+        key: K,
+        value: V,
+        now: Instant,
+        expiration: Expiration,
+        recompute: Duration,
+    ) -> Self {
+        ExpiringEntry {
+            key,
+            value,
+            inserted_at: now,
+            expiration,
+            jitter: Duration::ZERO,
+            recompute,
+            refreshed_at: now,
+            written: 0,
+            reloading: false,
+        }
+    }
+
+    /// When the entry expires as of `now`, which matters for entries
+    /// which expire by the wall clock, since it can move under the
+    /// Instants.
+    fn deadline(&self, timeout: Duration, now: Now) -> Option<Instant> {
+        let deadline = match self.expiration {
+            Expiration::Timeout => {
+                self.inserted_at.checked_add(timeout)
+            }
+            Expiration::At(deadline) => Some(deadline),
+            Expiration::Wall { at, instant } => {
+                let by_wall = match at.duration_since(now.wall) {
+                    Ok(left) => now.instant.checked_add(left),
+                    Err(past) => {
+                        now.instant.checked_sub(past.duration())
+                    }
+                };
+
+                Some(by_wall.map_or(instant, |w| w.min(instant)))
+            }
+            Expiration::Never => None,
+        }?;
+
+        Some(deadline.checked_sub(self.jitter).unwrap_or(deadline))
+    }
+
+    fn remaining(
+        &self,
+        timeout: Duration,
+        now: Now,
+    ) -> Option<Duration> {
+        self.deadline(timeout, now).map(|deadline| {
+            deadline.saturating_duration_since(now.instant)
+        })
+    }
+
+    fn is_expired(&self, timeout: Duration, now: Now) -> bool {
+        if let Expiration::Wall { at, .. } = self.expiration {
+            if now.wall > at {
+                return true;
+            }
+        }
+
+        self.deadline(timeout, now)
+            .is_some_and(|deadline| now.instant > deadline)
+    }
+
+    /// Decides whether to recommend refreshing the entry ahead of its
+    /// deadline, following the XFetch approach from "Optimal Probabilistic
+    /// Cache Stampede Prevention" (Vattani et al). The closer the entry is
+    /// to its deadline and the longer it took to compute, the likelier
+    /// this is to say yes. `r` is a random number in `(0, 1]`.
+    fn should_refresh(
+        &self,
+        timeout: Duration,
+        now: Now,
+        beta: f64,
+        r: f64,
+    ) -> bool {
+        let deadline = match self.deadline(timeout, now) {
+            Some(deadline) if !self.recompute.is_zero() => deadline,
+            _ => return false,
+        };
+
+        let head_start = self.recompute.as_secs_f64() * beta * -r.ln();
+
+        Duration::try_from_secs_f64(head_start)
+            .ok()
+            .and_then(|head_start| now.instant.checked_add(head_start))
+            .is_none_or(|refresh_at| refresh_at >= deadline)
+    }
+}
+
+/// Makes a [`Reload`] for an entry from its key, value, hash and which
+/// write put it there.
+type StartReload<K, V> =
+    Box<dyn Fn(&K, &V, KeyHash, u64) -> Reload + Send + Sync>;
+
+/// Reloads which have run, waiting to be put back into the cache.
+type Landed<K, V, S> =
+    Arc<Mutex<Vec<Reloaded<ExpiringCache<K, V, S>>>>>;
+
+/// Reloads entries some time after they were written.
+struct Refresh<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    after: Duration,
+    start: StartReload<K, V>,
+    landed: Landed<K, V, S>,
 }
 
 /// An LruCache which enforces that it will not return values which are older
@@ -21,13 +244,32 @@ struct ExpiringEntry<V> {
 /// eviction mechanism, which is to say that if you populate a cache and leave
 /// it alone for the timeout duration, it will still be at capacity. Elements
 /// will only evict on expiry if they are accessed past their timeout.
+///
+/// Instead of a single timeout the cache may be given an [`Expiry`] policy,
+/// which decides how long each entry lives from its key and value.
+///
+/// Unlike an LruCache, which only keeps the hashes of its keys, this keeps
+/// a copy of every key alongside its value, whether or not there is a
+/// policy or loader which looks at it. A cache of large keys takes up
+/// that much more memory for it.
 pub struct ExpiringCache<K, V, S = RandomState>
 where
     K: Eq + Hash,
     S: BuildHasher,
 {
-    cache: LruCache<K, ExpiringEntry<V>, S>,
+    cache: LruCache<K, ExpiringEntry<K, V>, S>,
     timeout: Duration,
+    expiry: Option<Box<dyn Expiry<K, V> + Send + Sync>>,
+    refresh: Option<Refresh<K, V, S>>,
+    spawner: Box<dyn Spawner + Send + Sync>,
+    writes: u64,
+    beta: f64,
+    jitter: f64,
+    rng: Rng,
+    stats: StatsRecorder,
+    events: EventSink,
+    #[cfg(feature = "tracing")]
+    tracer: Option<CacheTracer>,
 }
 
 impl<K, V> ExpiringCache<K, V, RandomState>
@@ -39,10 +281,27 @@ where
         capacity: usize,
         timeout: Duration,
     ) -> Self {
-        Self {
-            cache: LruCache::with_capacity(capacity),
+        Self::with_capacity_and_timeout_and_hash_builder(
+            capacity,
             timeout,
-        }
+            Default::default(),
+        )
+    }
+
+    /// Creates a new cache with a given capacity where the lifetime of each
+    /// entry is decided by an [`Expiry`] policy.
+    pub fn with_capacity_and_expiry<E>(
+        capacity: usize,
+        expiry: E,
+    ) -> Self
+    where
+        E: Expiry<K, V> + Send + Sync + 'static,
+    {
+        Self::with_capacity_and_expiry_and_hash_builder(
+            capacity,
+            expiry,
+            Default::default(),
+        )
     }
 }
 
@@ -63,102 +322,1756 @@ where
                 hash_builder,
             ),
             timeout,
+            expiry: None,
+            refresh: None,
+            spawner: Box::new(ThreadSpawner),
+            writes: 0,
+            beta: 1.0,
+            jitter: 0.0,
+            rng: Rng::new(),
+            stats: StatsRecorder::default(),
+            events: EventSink::default(),
+            #[cfg(feature = "tracing")]
+            tracer: None,
+        }
+    }
+
+    /// Creates a new cache with a given capacity, [`Expiry`] policy, and hash
+    /// builder.
+    pub fn with_capacity_and_expiry_and_hash_builder<E>(
+        capacity: usize,
+        expiry: E,
+        hash_builder: S,
+    ) -> Self
+    where
+        E: Expiry<K, V> + Send + Sync + 'static,
+    {
+        Self {
+            cache: LruCache::with_capacity_and_hash_builder(
+                capacity,
+                hash_builder,
+            ),
+            timeout: Duration::MAX,
+            expiry: Some(Box::new(expiry)),
+            refresh: None,
+            spawner: Box::new(ThreadSpawner),
+            writes: 0,
+            beta: 1.0,
+            jitter: 0.0,
+            rng: Rng::new(),
+            stats: StatsRecorder::default(),
+            events: EventSink::default(),
+            #[cfg(feature = "tracing")]
+            tracer: None,
         }
     }
 
-    /// Gets the timeout for this cache.
+    /// Gets the timeout for this cache. Caches created with an [`Expiry`]
+    /// policy have no timeout, which is reported as [`Duration::MAX`].
     pub fn get_timeout(&self) -> Duration {
         self.timeout
     }
 
     /// Sets the timeout for this cache. Setting this timeout will not evict any
     /// entries, it will only affect what entries are returned by calls to get
-    /// and get_mut.
+    /// and get_mut. Entries whose lifetime was decided by an [`Expiry`] policy
+    /// are unaffected.
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
 
+    /// Makes the cache reload entries through `loader` once they are older
+    /// than `after`, which should be shorter than the timeout. The first
+    /// get of an entry past that age still returns the current value
+    /// straight away, and hands a [`Reload`] to the cache's [`Spawner`] to
+    /// run without holding on to the cache. What it loaded goes in the next
+    /// time something is gotten from or written to the cache.
+    ///
+    /// The entry isn't reloaded again until the reload is put back and
+    /// another `after` has passed. If the reload worked the entry gets the
+    /// fresh value, unless it was written in the meantime, and if it
+    /// didn't the entry keeps its old value rather than expiring from
+    /// under callers while the loader is failing, at least until its own
+    /// expiry comes around.
+    pub fn set_refresh_after_write<L>(
+        &mut self,
+        after: Duration,
+        loader: L,
+    ) where
+        K: Clone + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+        S: 'static,
+        L: Loader<K, V> + Send + Sync + 'static,
+    {
+        let loader = Arc::new(loader);
+        let landed: Landed<K, V, S> = Arc::default();
+        let to_land = landed.clone();
+
+        self.refresh = Some(Refresh {
+            after,
+            start: Box::new(move |k, old, hash_k, written| {
+                let (k, old) = (k.clone(), old.clone());
+                let (loader, landed) =
+                    (loader.clone(), to_land.clone());
+
+                Reload::new(move || {
+                    let started = Instant::now();
+                    let reloaded = loader.reload(&k, &old);
+                    let took = started.elapsed();
+
+                    let reloaded =
+                        Reloaded::new(move |cache: &mut Self| {
+                            cache.finish_reload(
+                                hash_k, written, reloaded, took,
+                            )
+                        });
+                    landed
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(reloaded);
+                })
+            }),
+            landed,
+        });
+    }
+
+    /// Has reloads run by `spawner` rather than each on a thread of its
+    /// own, such as on an async runtime's blocking pool.
+    pub fn set_reload_spawner<P>(&mut self, spawner: P)
+    where
+        P: Spawner + Send + Sync + 'static,
+    {
+        self.spawner = Box::new(spawner);
+    }
+
+    /// Stops reloading entries after they were written.
+    pub fn clear_refresh_after_write(&mut self) {
+        self.refresh = None;
+    }
+
+    /// Sets how eagerly [`ExpiringCache::get_with_refresh`] recommends
+    /// refreshing entries before they expire. The default of `1.0` is
+    /// usually right; larger values refresh earlier and smaller values
+    /// later, with `0.0` turning early refreshes off entirely.
+    pub fn set_early_refresh_beta(&mut self, beta: f64) {
+        self.beta = beta.max(0.0);
+    }
+
+    /// Sets the largest fraction of an entry's lifetime which may be
+    /// randomly shaved off of it when it is inserted, so that entries
+    /// inserted at the same time do not all expire at the same time. For
+    /// example `0.1` makes entries live somewhere between 90% and 100% of
+    /// their lifetime. Defaults to `0.0`, which is no jitter at all, and
+    /// only affects entries inserted afterwards.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    /// The most items the cache holds before it starts evicting.
+    pub fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    /// Starts or stops keeping track of how each item has been gotten
+    /// since it was written, for [`Cache::entry_info`]. It is off to begin
+    /// with, and turning it off throws away what was tracked. When each
+    /// item was written and how long it has left are known either way.
+    pub fn set_track_metadata(&mut self, track: bool) {
+        self.cache.set_track_metadata(track);
+    }
+
+    /// Starts or stops keeping [`CacheStats`] for this cache. They are off
+    /// to begin with, and turning them off throws away what was recorded.
+    pub fn set_record_stats(&mut self, record: bool) {
+        self.stats.set_recording(record);
+    }
+
+    /// What the cache has been up to since it started recording stats, or
+    /// None if it isn't recording them. Expired items are counted as they
+    /// are found and thrown out, rather than the moment they expire.
+    pub fn stats(&self) -> Option<CacheStats> {
+        self.stats.snapshot()
+    }
+
+    /// Sets every stat back to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// Inserts an item into the cache along with how long it took to
+    /// compute, which [`ExpiringCache::get_with_refresh`] uses to decide
+    /// when to recommend refreshing it. Items inserted without a recompute
+    /// time are never refreshed early.
+    pub fn insert_with_recompute_time(
+        &mut self,
+        k: K,
+        v: V,
+        recompute: Duration,
+    ) -> Option<V> {
+        self.insert_entry(k, v, recompute)
+    }
+
+    /// Inserts an item into the cache which expires at a given wall-clock
+    /// time, such as one taken from an HTTP `Expires` header, regardless
+    /// of the timeout or [`Expiry`] policy of the cache.
+    pub fn insert_with_deadline(
+        &mut self,
+        k: K,
+        v: V,
+        deadline: SystemTime,
+    ) -> Option<V> {
+        let now = Now::new();
+
+        self.insert_expiring(k, v, Expiration::wall(deadline, now), now)
+    }
+
+    /// Inserts an item which expires as it is told to, rather than as the
+    /// timeout or [`Expiry`] policy of the cache would have it.
+    fn insert_expiring(
+        &mut self,
+        k: K,
+        v: V,
+        expiration: Expiration,
+        now: Now,
+    ) -> Option<V> {
+        self.land_reloads();
+        let hash_k = self.cache.hash_k(&k);
+        let old_entry = self.cache.remove_hashed(hash_k);
+
+        self.put(
+            hash_k,
+            ExpiringEntry::new(
+                k,
+                v,
+                now.instant,
+                expiration,
+                Duration::ZERO,
+            ),
+            now,
+        );
+        self.record_replaced(hash_k, old_entry.as_ref(), now);
+
+        old_entry.map(|e| e.value)
+    }
+
+    /// Copies every entry which has not expired out of the cache, from the
+    /// oldest to the youngest, with their expirations as wall-clock times.
+    pub fn snapshot(&self) -> Vec<SnapshotEntry<K, V>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let now = Now::new();
+
+        let mut entries: Vec<SnapshotEntry<K, V>> = self
+            .cache
+            .iter_hashed()
+            .filter(|(_, e)| !e.is_expired(self.timeout, now))
+            .map(|(_, e)| SnapshotEntry {
+                key: e.key.clone(),
+                value: e.value.clone(),
+                expires_at: e.remaining(self.timeout, now).and_then(
+                    |remaining| now.wall.checked_add(remaining),
+                ),
+            })
+            .collect();
+
+        entries.reverse();
+        entries
+    }
+
+    /// Puts entries from [`ExpiringCache::snapshot`] back into the cache in
+    /// order, so that the oldest entries are still the first to be evicted.
+    /// They keep whatever lifetime they had left when the snapshot was
+    /// taken, and any which expired in the meantime are skipped. Returns
+    /// how many entries were restored.
+    pub fn restore<I>(&mut self, entries: I) -> usize
+    where
+        I: IntoIterator<Item = SnapshotEntry<K, V>>,
+    {
+        let now = Now::new();
+        let mut restored = 0;
+
+        for entry in entries {
+            let expiration = match entry.expires_at {
+                Some(expires_at) if expires_at <= now.wall => continue,
+                Some(expires_at) => Expiration::wall(expires_at, now),
+                None => Expiration::Never,
+            };
+
+            self.insert_expiring(
+                entry.key,
+                entry.value,
+                expiration,
+                now,
+            );
+            restored += 1;
+        }
+
+        restored
+    }
+
+    /// Gets an item from the cache like [`Cache::get`], along with whether
+    /// it would be a good idea to refresh it now. The chance of that rises
+    /// as the item gets closer to expiring, so that when many callers are
+    /// reading a hot item only one or two of them go off to refresh it,
+    /// and they do so before it expires rather than all at once after.
+    pub fn get_with_refresh<Q>(&mut self, k: &Q) -> Option<(&V, bool)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = Now::new();
+        let timeout = self.timeout;
+        let beta = self.beta;
+        let r = self.rng.next_f64();
+
+        let hash_k = self.cache.hash_k(k);
+        let hit_age = self.get_entry_age(hash_k, now);
+        self.record_lookup(hit_age);
+
+        self.cache.peek_hashed(hash_k).map(|e| {
+            let refresh = e.should_refresh(timeout, now, beta, r);
+            let v: &V = &e.value;
+            (v, refresh)
+        })
+    }
+
+    fn insert_entry(
+        &mut self,
+        k: K,
+        v: V,
+        recompute: Duration,
+    ) -> Option<V> {
+        self.land_reloads();
+        let now = Now::new();
+        let hash_k = self.cache.hash_k(&k);
+        let old_entry = self.cache.remove_hashed(hash_k);
+        let replacing = old_entry
+            .as_ref()
+            .filter(|e| !e.is_expired(self.timeout, now))
+            .map(|e| e.remaining(self.timeout, now));
+
+        self.write_entry(hash_k, k, v, recompute, replacing, now);
+        self.record_replaced(hash_k, old_entry.as_ref(), now);
+
+        old_entry.map(|e| e.value)
+    }
+
+    /// Puts a freshly written value into the cache, letting the [`Expiry`]
+    /// policy decide when it expires. `replacing` is how long the value it
+    /// replaces had left, or None if it is not replacing anything.
+    fn write_entry(
+        &mut self,
+        hash_k: KeyHash,
+        k: K,
+        v: V,
+        recompute: Duration,
+        replacing: Option<Option<Duration>>,
+        now: Now,
+    ) {
+        let expiration = match &self.expiry {
+            Some(expiry) => {
+                let lifetime = match replacing {
+                    Some(remaining) => expiry.expire_after_update(
+                        &k,
+                        &v,
+                        now.instant,
+                        remaining,
+                    ),
+                    None => {
+                        expiry.expire_after_create(&k, &v, now.instant)
+                    }
+                };
+
+                Expiration::after(now.instant, lifetime)
+            }
+            None => Expiration::Timeout,
+        };
+
+        let mut entry = ExpiringEntry::new(
+            k,
+            v,
+            now.instant,
+            expiration,
+            recompute,
+        );
+
+        if self.jitter > 0.0 {
+            if let Some(lifetime) = entry.remaining(self.timeout, now) {
+                entry.jitter =
+                    lifetime.mul_f64(self.jitter * self.rng.next_f64());
+            }
+        }
+
+        self.put(hash_k, entry, now);
+    }
+
+    /// Puts an entry into the cache, noting whether something had to be
+    /// evicted to make room for it.
+    fn put(
+        &mut self,
+        hash_k: KeyHash,
+        mut entry: ExpiringEntry<K, V>,
+        now: Now,
+    ) {
+        self.writes += 1;
+        entry.written = self.writes;
+
+        let evicting = self.cache.peek_hashed(hash_k).is_none()
+            && !self.cache.is_empty()
+            && self.cache.len() >= self.cache.capacity();
+
+        let oldest = match self.cache.oldest_hash() {
+            Some(oldest) if evicting => oldest,
+            _ => {
+                self.cache.insert_hashed(hash_k, entry);
+                return;
+            }
+        };
+        let (age, expired) = match self.cache.peek_hashed(oldest) {
+            Some(e) => (
+                now.instant.saturating_duration_since(e.inserted_at),
+                e.is_expired(self.timeout, now),
+            ),
+            None => (Duration::ZERO, false),
+        };
+
+        // the oldest item having expired anyway, it counts as an expiry
+        // rather than as something thrown out for want of room
+        if expired {
+            self.stats.record(|s| {
+                s.expirations += 1;
+                s.expiry_ages.record(age);
+            });
+            self.events.emit(CacheEvent::Expired(oldest));
+        } else {
+            self.stats.record(|s| {
+                s.capacity_evictions += 1;
+                s.eviction_ages.record(age);
+            });
+            self.events.emit(CacheEvent::Evicted(oldest));
+        }
+        #[cfg(feature = "tracing")]
+        if let Some(tracer) = &self.tracer {
+            let cause = if expired {
+                EvictionCause::Expired
+            } else {
+                EvictionCause::Capacity
+            };
+            tracer.evicted(oldest, cause);
+        }
+
+        self.cache.insert_hashed(hash_k, entry);
+    }
+
+    /// Counts a write as an insert or an update, depending on what it
+    /// replaced. Writing over an expired entry counts as an insert.
+    fn record_replaced(
+        &mut self,
+        hash_k: KeyHash,
+        old_entry: Option<&ExpiringEntry<K, V>>,
+        now: Now,
+    ) {
+        let timeout = self.timeout;
+
+        self.stats.record(|s| match old_entry {
+            Some(e) if e.is_expired(timeout, now) => {
+                s.expirations += 1;
+                s.expiry_ages.record(
+                    now.instant
+                        .saturating_duration_since(e.inserted_at),
+                );
+                s.inserts += 1;
+            }
+            Some(_) => s.updates += 1,
+            None => s.inserts += 1,
+        });
+
+        match old_entry {
+            Some(e) if e.is_expired(timeout, now) => {
+                self.events.emit(CacheEvent::Expired(hash_k));
+                self.events.emit(CacheEvent::Inserted(hash_k));
+                #[cfg(feature = "tracing")]
+                if let Some(tracer) = &self.tracer {
+                    tracer.expired(hash_k);
+                }
+            }
+            Some(_) => self.events.emit(CacheEvent::Updated(hash_k)),
+            None => self.events.emit(CacheEvent::Inserted(hash_k)),
+        }
+    }
+
+    /// Counts a get as a hit on an item of the given age, or a miss.
+    fn record_lookup(&mut self, hit_age: Option<Duration>) {
+        match hit_age {
+            Some(age) => self.stats.record(|s| {
+                s.hits += 1;
+                s.hit_ages.record(age);
+            }),
+            None => self.stats.record(|s| s.misses += 1),
+        }
+    }
+
+    /// Gets an entry like [`ExpiringCache::get_entry_mut`], giving back
+    /// how old it was when found, before any refresh made it younger.
+    fn get_entry_age(
+        &mut self,
+        hash_k: KeyHash,
+        now: Now,
+    ) -> Option<Duration> {
+        let written = self.cache.peek_hashed(hash_k)?.inserted_at;
+
+        self.get_entry_mut(hash_k, now)
+            .map(|_| now.instant.saturating_duration_since(written))
+    }
+
+    /// Finds an entry which has not expired, promoting it, reloading it if
+    /// it is due for a refresh, and letting the [`Expiry`] policy know it
+    /// was read. Expired entries are removed.
+    fn get_entry_mut(
+        &mut self,
+        hash_k: KeyHash,
+        now: Now,
+    ) -> Option<&mut ExpiringEntry<K, V>> {
+        self.land_reloads();
+        let timeout = self.timeout;
+
+        let entry = self.cache.get_mut_hashed(hash_k)?;
+
+        if entry.is_expired(timeout, now) {
+            let age = now
+                .instant
+                .saturating_duration_since(entry.inserted_at);
+            self.cache.remove_hashed(hash_k);
+            self.stats.record(|s| {
+                s.expirations += 1;
+                s.expiry_ages.record(age);
+            });
+            self.events.emit(CacheEvent::Expired(hash_k));
+            #[cfg(feature = "tracing")]
+            if let Some(tracer) = &self.tracer {
+                tracer.expired(hash_k);
+            }
+            return None;
+        }
+
+        if let Some(refresh) = &self.refresh {
+            let due = !entry.reloading
+                && entry
+                    .refreshed_at
+                    .checked_add(refresh.after)
+                    .is_some_and(|due| now.instant >= due);
+
+            if due {
+                entry.refreshed_at = now.instant;
+                entry.reloading = true;
+
+                #[allow(unused_mut)]
+                let mut reload = (refresh.start)(
+                    &entry.key,
+                    &entry.value,
+                    hash_k,
+                    entry.written,
+                );
+                #[cfg(feature = "tracing")]
+                {
+                    reload.span = self
+                        .tracer
+                        .as_ref()
+                        .map(|tracer| tracer.load_span(Some(hash_k)));
+                }
+                self.spawner.spawn(reload);
+            }
+        }
+
+        self.events.emit(CacheEvent::Accessed(hash_k));
+
+        let entry = self.cache.peek_mut_hashed(hash_k)?;
+        let explicit_deadline =
+            matches!(entry.expiration, Expiration::Wall { .. });
+
+        if let (Some(expiry), false) = (&self.expiry, explicit_deadline)
+        {
+            let lifetime = expiry.expire_after_read(
+                &entry.key,
+                &entry.value,
+                now.instant,
+                entry.remaining(timeout, now),
+            );
+            entry.expiration = Expiration::after(now.instant, lifetime);
+            entry.jitter = Duration::ZERO;
+        }
+
+        Some(entry)
+    }
+
+    /// Puts back what every reload which has run since this was last
+    /// called loaded.
+    fn land_reloads(&mut self) {
+        let landed = match &self.refresh {
+            Some(refresh) => std::mem::take(
+                &mut *refresh
+                    .landed
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()),
+            ),
+            None => return,
+        };
+
+        for reloaded in landed {
+            reloaded.apply(self);
+        }
+    }
+
+    /// Puts what a [`Reload`] loaded for the entry under `hash_k` back,
+    /// if the entry is still the one put there by the write `written`.
+    fn finish_reload(
+        &mut self,
+        hash_k: KeyHash,
+        written: u64,
+        reloaded: Result<V, Box<dyn Error + Send + Sync>>,
+        took: Duration,
+    ) {
+        #[cfg(feature = "tracing")]
+        if let Some(tracer) = &self.tracer {
+            tracer.loaded(reloaded.is_ok(), took);
+        }
+        self.stats.record(|s| {
+            match reloaded {
+                Ok(_) => s.load_successes += 1,
+                Err(_) => s.load_failures += 1,
+            }
+            s.total_load_time += took;
+        });
+
+        let now = Now::new();
+        let timeout = self.timeout;
+        let entry = match self.cache.peek_mut_hashed(hash_k) {
+            Some(entry) if entry.written == written => entry,
+            _ => return,
+        };
+        entry.reloading = false;
+
+        let v = match reloaded {
+            Ok(v) if !entry.is_expired(timeout, now) => v,
+            _ => return,
+        };
+        let remaining = entry.remaining(timeout, now);
+
+        if let Some(old_entry) = self.cache.remove_hashed(hash_k) {
+            match old_entry.expiration {
+                // a deadline the caller gave outlasts any reload, just as
+                // it isn't moved by the Expiry policy on reads
+                Expiration::Wall { .. } => self.put(
+                    hash_k,
+                    ExpiringEntry::new(
+                        old_entry.key,
+                        v,
+                        now.instant,
+                        old_entry.expiration,
+                        took,
+                    ),
+                    now,
+                ),
+                _ => self.write_entry(
+                    hash_k,
+                    old_entry.key,
+                    v,
+                    took,
+                    Some(remaining),
+                    now,
+                ),
+            }
+            self.events.emit(CacheEvent::Updated(hash_k));
+        }
+    }
+
+    /// Removes every entry which has expired, returning how many were
+    /// removed. This walks the whole cache, so it is best done every now
+    /// and again rather than on every access.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Now::new();
+        let expired = self.expired_hashes(now);
+
+        self.purge_hashed(&expired, now)
+    }
+
+    /// Collects the hashes of every entry which has expired by `now`.
+    pub(crate) fn expired_hashes(&self, now: Now) -> Vec<KeyHash> {
+        self.cache
+            .iter_hashed()
+            .filter(|(_, e)| e.is_expired(self.timeout, now))
+            .map(|(hash_k, _)| hash_k)
+            .collect()
+    }
+
+    /// Removes the entries under the given hashes which are still expired
+    /// by `now`, returning how many were removed. Entries which have been
+    /// replaced since their hash was collected are left alone.
+    pub(crate) fn purge_hashed(
+        &mut self,
+        hashes: &[KeyHash],
+        now: Now,
+    ) -> usize {
+        let mut purged = 0;
+
+        for hash_k in hashes {
+            let expired_age = self
+                .cache
+                .peek_hashed(*hash_k)
+                .filter(|e| e.is_expired(self.timeout, now))
+                .map(|e| {
+                    now.instant.saturating_duration_since(e.inserted_at)
+                });
+
+            if let Some(age) = expired_age {
+                self.cache.remove_hashed(*hash_k);
+                self.stats.record(|s| s.expiry_ages.record(age));
+                self.events.emit(CacheEvent::Expired(*hash_k));
+                #[cfg(feature = "tracing")]
+                if let Some(tracer) = &self.tracer {
+                    tracer.expired(*hash_k);
+                }
+                purged += 1;
+            }
+        }
+
+        self.stats.record(|s| s.expirations += purged as u64);
+
+        purged
+    }
+
     /// Gets the time a particular key was inserted into the cache, if present.
     /// Returns Some even if the insertion time is older than the timeout.
+    /// This makes the item the youngest in the cache, which
+    /// [`Cache::entry_info`] doesn't.
     pub fn get_inserted_at<Q>(&mut self, k: &Q) -> Option<Instant>
     where
-        Q: Hash + Eq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
         self.cache.get(k).map(|e| e.inserted_at)
     }
 }
 
-impl<K, V, S> Cache<K, V> for ExpiringCache<K, V, S>
+impl<K, V, S> Cache for ExpiringCache<K, V, S>
 where
     K: Eq + Hash,
     S: BuildHasher,
 {
+    type Key = K;
+    type Value = V;
+
     fn insert(&mut self, k: K, v: V) -> Option<V> {
-        self.cache
-            .insert(
-                k,
-                ExpiringEntry {
-                    value: v,
-                    inserted_at: Instant::now(),
-                },
-            )
-            .map(|e| e.value)
+        self.insert_entry(k, v, Duration::ZERO)
     }
 
-    fn get_mut<'a, Q>(&'a mut self, k: &Q) -> Option<&'a mut V>
+    fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
     where
-        Q: Hash + Eq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
-        if let Some(inserted_at) = self.get_inserted_at(k) {
-            if inserted_at.elapsed() > self.timeout {
-                self.cache.remove(k);
-                return None;
-            }
+        let hash_k = self.cache.hash_k(k);
+        let hit_age = self.get_entry_age(hash_k, Now::new());
+        self.record_lookup(hit_age);
+        #[cfg(feature = "tracing")]
+        if let (None, Some(tracer)) = (hit_age, &self.tracer) {
+            tracer.miss(hash_k);
         }
 
-        return self.cache.get_mut(k).map(|e| &mut e.value);
+        self.cache.peek_mut_hashed(hash_k).map(|e| &mut e.value)
+    }
+
+    /// Expired items are not returned, but since this cannot change the
+    /// cache they are not removed either. Nor is the [`Expiry`] policy told
+    /// about the read, and items due for a refresh are not reloaded.
+    fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = Now::new();
+
+        self.cache
+            .peek_hashed(self.cache.hash_k(k))
+            .filter(|e| !e.is_expired(self.timeout, now))
+            .map(|e| &e.value)
+    }
+
+    fn can_peek(&self) -> bool {
+        true
+    }
+
+    /// Expired items have no info, but are not removed either.
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = Now::new();
+        let hash_k = self.cache.hash_k(k);
+        let entry = self
+            .cache
+            .peek_hashed(hash_k)
+            .filter(|e| !e.is_expired(self.timeout, now))?;
+
+        self.cache.entry_info_hashed(hash_k).map(|info| EntryInfo {
+            created_at: Some(entry.inserted_at),
+            time_to_live: entry.remaining(self.timeout, now),
+            ..info
+        })
+    }
+
+    fn key_hash<Q>(&self, k: &Q) -> KeyHash
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.hash_k(k)
+    }
+
+    /// This behaves exactly like a get, removing the item if it expired and
+    /// reloading it if it is due for a refresh.
+    fn touch_hash(&mut self, hash_k: KeyHash) {
+        self.get_entry_mut(hash_k, Now::new());
     }
 
     fn remove<Q>(&mut self, k: &Q) -> Option<V>
     where
-        Q: Hash + Eq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
-        self.cache.remove(k).map(|e| e.value)
+        let hash_k = self.cache.hash_k(k);
+        let v = self.cache.remove_hashed(hash_k).map(|e| e.value);
+
+        if v.is_some() {
+            self.stats.record(|s| s.removals += 1);
+            self.events.emit(CacheEvent::Removed(hash_k));
+        }
+
+        v
     }
 
     fn clear(&mut self) {
+        let len = self.cache.len() as u64;
+        self.stats.record(|s| s.cleared += len);
+        self.events.emit(CacheEvent::Cleared);
         self.cache.clear();
     }
 
     fn len(&self) -> usize {
         self.cache.len()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::time::{Duration, Instant};
+    fn set_record_stats(&mut self, record: bool) {
+        ExpiringCache::set_record_stats(self, record)
+    }
 
-    use crate::{Cache, ExpiringCache};
+    fn stats(&self) -> Option<CacheStats> {
+        ExpiringCache::stats(self)
+    }
 
-    use super::ExpiringEntry;
+    fn reset_stats(&mut self) {
+        ExpiringCache::reset_stats(self)
+    }
+}
 
-    #[test]
-    fn readme_snippet() {
-        let mut cache: ExpiringCache<u64, u64> =
-            ExpiringCache::with_capacity_and_timeout(
-                1,
-                Duration::from_secs(30),
-            );
-        // simulate adding something 35 seconds ago
+impl<K, V, S> EventSource for ExpiringCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn subscribe(
+        &mut self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> CacheEvents {
+        self.events.subscribe(capacity, overflow)
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<K, V, S> Traceable for ExpiringCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn attach_tracer(&mut self, tracer: CacheTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    fn detach_tracer(&mut self) -> Option<CacheTracer> {
+        self.tracer.take()
+    }
+}
+
+/// Shows the items from the youngest to the oldest, expired or not.
+impl<K, V, S> fmt::Debug for ExpiringCache<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpiringCache")
+            .field("capacity", &self.capacity())
+            .field("timeout", &self.timeout)
+            .field("entries", &DebugEntries(self))
+            .finish()
+    }
+}
+
+struct DebugEntries<'a, K, V, S>(&'a ExpiringCache<K, V, S>)
+where
+    K: Eq + Hash,
+    S: BuildHasher;
+
+impl<K, V, S> fmt::Debug for DebugEntries<'_, K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.0
+                    .cache
+                    .iter_hashed()
+                    .map(|(_, e)| (&e.key, &e.value)),
+            )
+            .finish()
+    }
+}
+
+/// Expired entries are included, with a `ttl_ms` of zero, as are their
+/// keys.
+#[cfg(feature = "serde")]
+impl<K, V, S> crate::json::JsonDump for ExpiringCache<K, V, S>
+where
+    K: Eq + Hash + serde::Serialize,
+    V: serde::Serialize,
+    S: BuildHasher,
+{
+    fn to_json(&self) -> serde_json::Value {
+        let now = Now::new();
+        let entries: Vec<_> = self
+            .cache
+            .iter_hashed()
+            .map(|(hash_k, e)| {
+                let info = self
+                    .cache
+                    .entry_info_hashed(hash_k)
+                    .unwrap_or_else(EntryInfo::bare);
+                let mut entry = crate::json::entry_json(
+                    hash_k,
+                    &e.value,
+                    EntryInfo {
+                        created_at: Some(e.inserted_at),
+                        time_to_live: e.remaining(self.timeout, now),
+                        ..info
+                    },
+                    now.instant,
+                );
+                entry.insert(
+                    "key".to_owned(),
+                    serde_json::to_value(&e.key)
+                        .unwrap_or(serde_json::Value::Null),
+                );
+                entry
+            })
+            .collect();
+
+        serde_json::json!({
+            "capacity": self.capacity(),
+            "len": self.len(),
+            "entries": entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use std::{
+        error::Error,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::SystemTime,
+    };
+
+    use crate::{
+        Cache, CacheEvent, EventSource, ExpiringCache, Expiry, Loader,
+        Overflow, Reload, SnapshotEntry, Spawner,
+    };
+
+    use super::{Expiration, ExpiringEntry, Now};
+
+    /// An entry for `k` holding `k`, written just now and expiring by the
+    /// cache's timeout, for tests to override the fields they care about.
+    fn entry(k: u64) -> ExpiringEntry<u64, u64> {
+        let now = Instant::now();
+
+        ExpiringEntry {
+            key: k,
+            value: k,
+            inserted_at: now,
+            expiration: Expiration::Timeout,
+            jitter: Duration::ZERO,
+            recompute: Duration::ZERO,
+            refreshed_at: now,
+            written: 0,
+            reloading: false,
+        }
+    }
+
+    #[test]
+    fn readme_snippet() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                1,
+                Duration::from_secs(30),
+            );
+        // simulate adding something 35 seconds ago
         // this is equivalent to cache.insert(1, 1) followed by sleep(35)
         cache.cache.insert(
             1,
             ExpiringEntry {
-                value: 1,
                 inserted_at: Instant::now() - Duration::from_secs(35),
+                ..entry(1)
             },
         );
         assert_eq!(None, cache.get(&1));
     }
+
+    /// Values are their own lifetime in seconds, and zero means forever.
+    struct SecondsInValue;
+
+    impl Expiry<u64, u64> for SecondsInValue {
+        fn expire_after_create(
+            &self,
+            _k: &u64,
+            v: &u64,
+            _now: Instant,
+        ) -> Option<Duration> {
+            match v {
+                0 => None,
+                v => Some(Duration::from_secs(*v)),
+            }
+        }
+
+        fn expire_after_read(
+            &self,
+            _k: &u64,
+            _v: &u64,
+            _now: Instant,
+            remaining: Option<Duration>,
+        ) -> Option<Duration> {
+            // reading an entry takes ten seconds off of its life
+            remaining.map(|r| r.saturating_sub(Duration::from_secs(10)))
+        }
+    }
+
+    fn remaining(
+        cache: &mut ExpiringCache<u64, u64>,
+        k: u64,
+    ) -> Option<Duration> {
+        let hash_k = cache.cache.hash_k(&k);
+        let timeout = cache.timeout;
+        let entry = cache.cache.peek_mut_hashed(hash_k).unwrap();
+        entry.remaining(timeout, Now::new())
+    }
+
+    #[test]
+    fn test_expiry_policy() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_expiry(5, SecondsInValue);
+
+        cache.insert(1, 0);
+        cache.insert(2, 30);
+        cache.insert(3, 15);
+
+        assert_eq!(None, remaining(&mut cache, 1));
+        assert!(
+            remaining(&mut cache, 2).unwrap() > Duration::from_secs(20)
+        );
+
+        // reading wears entries down
+        assert_eq!(Some(&0), cache.get(&1u64));
+        assert_eq!(Some(&15), cache.get(&3u64));
+        assert_eq!(None, remaining(&mut cache, 1));
+        assert!(
+            remaining(&mut cache, 3).unwrap() <= Duration::from_secs(5)
+        );
+
+        // updating the value runs the policy over again
+        assert_eq!(Some(30), cache.insert(2, 0));
+        assert_eq!(None, remaining(&mut cache, 2));
+
+        // and something past its deadline is gone
+        cache.cache.insert(
+            4,
+            ExpiringEntry {
+                inserted_at: Instant::now() - Duration::from_secs(5),
+                expiration: Expiration::At(
+                    Instant::now() - Duration::from_secs(1),
+                ),
+                ..entry(4)
+            },
+        );
+        assert_eq!(None, cache.get(&4u64));
+        assert_eq!(3, cache.len());
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(30),
+            );
+        cache.insert(1, 1);
+        cache.cache.insert(
+            2,
+            ExpiringEntry {
+                inserted_at: Instant::now() - Duration::from_secs(35),
+                ..entry(2)
+            },
+        );
+        cache.insert(3, 3);
+
+        assert_eq!(1, cache.purge_expired());
+        assert_eq!(2, cache.len());
+        assert_eq!(0, cache.purge_expired());
+        assert_eq!(Some(&1), cache.get(&1u64));
+        assert_eq!(Some(&3), cache.get(&3u64));
+    }
+
+    #[test]
+    fn test_entry_info() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_secs(30),
+            );
+        cache.insert(1, 1);
+
+        let info = cache.entry_info(&1).unwrap();
+        assert!(info.created_at.is_some());
+        assert_eq!(None, info.access_count);
+        assert!(info.time_to_live.unwrap() <= Duration::from_secs(30));
+        assert!(info.time_to_live.unwrap() > Duration::from_secs(29));
+
+        cache.set_track_metadata(true);
+        cache.insert(2, 2);
+        cache.get(&2);
+        assert_eq!(Some(1), cache.entry_info(&2).unwrap().access_count);
+
+        let hash_k = cache.key_hash(&2);
+        cache.cache.peek_mut_hashed(hash_k).unwrap().inserted_at =
+            Instant::now() - Duration::from_secs(35);
+        assert_eq!(None, cache.entry_info(&2));
+        assert_eq!(2, cache.len());
+    }
+
+    #[test]
+    fn test_events() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_secs(30),
+            );
+        let events = cache.subscribe(2, Overflow::DropOldest);
+        let hash = |k: u64| cache.key_hash(&k);
+        let (one, two, three) = (hash(1), hash(2), hash(3));
+
+        cache.insert(1, 1);
+        cache.cache.peek_mut_hashed(one).unwrap().inserted_at =
+            Instant::now() - Duration::from_secs(35);
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(
+            vec![CacheEvent::Inserted(one), CacheEvent::Expired(one)],
+            std::iter::from_fn(|| events.try_recv())
+                .collect::<Vec<_>>()
+        );
+
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        cache.insert(3, 3);
+        assert_eq!(
+            vec![CacheEvent::Evicted(two), CacheEvent::Inserted(three)],
+            std::iter::from_fn(|| events.try_recv())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(3, events.dropped());
+    }
+
+    #[test]
+    fn test_early_refresh() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(3600),
+            );
+
+        // nothing is recommended for refresh without a recompute time,
+        // nor an hour away from expiring after a one second recompute
+        cache.insert(1, 1);
+        cache.insert_with_recompute_time(2, 2, Duration::from_secs(1));
+        for _ in 0..100 {
+            assert_eq!(
+                Some((&1, false)),
+                cache.get_with_refresh(&1u64)
+            );
+            assert_eq!(
+                Some((&2, false)),
+                cache.get_with_refresh(&2u64)
+            );
+        }
+
+        // something a minute away from expiring which took an hour to
+        // compute is practically always recommended
+        cache.cache.insert(
+            3,
+            ExpiringEntry {
+                expiration: Expiration::At(
+                    Instant::now() + Duration::from_secs(60),
+                ),
+                recompute: Duration::from_secs(3600),
+                ..entry(3)
+            },
+        );
+        let refreshes = (0..100)
+            .filter(|_| cache.get_with_refresh(&3u64).unwrap().1)
+            .count();
+        assert!(refreshes > 90);
+
+        // unless early refreshes are turned off
+        cache.set_early_refresh_beta(0.0);
+        assert_eq!(Some((&3, false)), cache.get_with_refresh(&3u64));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                100,
+                Duration::from_secs(100),
+            );
+        cache.set_jitter(0.5);
+
+        for k in 0..100 {
+            cache.insert(k, k);
+        }
+
+        let jitters: Vec<Duration> =
+            cache.cache.iter_hashed().map(|(_, e)| e.jitter).collect();
+        assert!(jitters.iter().all(|j| *j <= Duration::from_secs(50)));
+        assert!(jitters.iter().any(|j| *j != jitters[0]));
+        assert_eq!(Some(&7), cache.get(&7u64));
+    }
+
+    /// Counts reloads, and fails every other one.
+    struct FlakyLoader(Arc<AtomicU64>);
+
+    impl Loader<u64, u64> for FlakyLoader {
+        fn reload(
+            &self,
+            _k: &u64,
+            old: &u64,
+        ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+            match self.0.fetch_add(1, Ordering::SeqCst) % 2 {
+                0 => Ok(old + 1),
+                _ => Err("failed to reload".into()),
+            }
+        }
+    }
+
+    /// Holds on to reloads until the test runs them.
+    #[derive(Clone, Default)]
+    struct HeldReloads(Arc<Mutex<Vec<Reload>>>);
+
+    impl HeldReloads {
+        fn run(&self) {
+            for reload in std::mem::take(&mut *self.0.lock().unwrap()) {
+                reload.run();
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    impl Spawner for HeldReloads {
+        fn spawn(&self, reload: Reload) {
+            self.0.lock().unwrap().push(reload);
+        }
+    }
+
+    #[test]
+    fn test_refresh_after_write() {
+        let reloads = Arc::new(AtomicU64::new(0));
+        let held = HeldReloads::default();
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(3600),
+            );
+        cache.set_refresh_after_write(
+            Duration::from_secs(60),
+            FlakyLoader(reloads.clone()),
+        );
+        cache.set_reload_spawner(held.clone());
+
+        // fresh entries are left alone
+        cache.insert(1, 1);
+        assert_eq!(Some(&1), cache.get(&1u64));
+        assert_eq!(0, reloads.load(Ordering::SeqCst));
+
+        // something written two minutes ago keeps its value until the
+        // reload it asks for has run, and only asks once
+        let written_at = Instant::now() - Duration::from_secs(120);
+        cache.cache.insert(
+            2,
+            ExpiringEntry {
+                inserted_at: written_at,
+                refreshed_at: written_at,
+                ..entry(2)
+            },
+        );
+        assert_eq!(Some(&2), cache.get(&2u64));
+        assert_eq!(Some(&2), cache.get(&2u64));
+        assert_eq!(0, reloads.load(Ordering::SeqCst));
+        assert_eq!(1, held.len());
+        held.run();
+        assert_eq!(Some(&3), cache.get(&2u64));
+        assert_eq!(1, reloads.load(Ordering::SeqCst));
+        assert!(cache.get_inserted_at(&2u64).unwrap() > written_at);
+        assert_eq!(0, held.len());
+
+        // and a failed reload keeps the old value
+        cache.cache.insert(
+            3,
+            ExpiringEntry {
+                inserted_at: written_at,
+                refreshed_at: written_at,
+                ..entry(3)
+            },
+        );
+        assert_eq!(Some(&3), cache.get(&3u64));
+        held.run();
+        assert_eq!(Some(&3), cache.get(&3u64));
+        assert_eq!(2, reloads.load(Ordering::SeqCst));
+        assert_eq!(Some(written_at), cache.get_inserted_at(&3u64));
+    }
+
+    #[test]
+    fn test_reload_on_thread() {
+        let reloads = Arc::new(AtomicU64::new(0));
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(3600),
+            );
+        cache.set_refresh_after_write(
+            Duration::from_secs(60),
+            FlakyLoader(reloads.clone()),
+        );
+
+        let written_at = Instant::now() - Duration::from_secs(120);
+        cache.cache.insert(
+            1,
+            ExpiringEntry {
+                inserted_at: written_at,
+                refreshed_at: written_at,
+                ..entry(1)
+            },
+        );
+        assert_eq!(Some(&1), cache.get(&1u64));
+
+        // nothing has to run it, it goes in once it is done
+        for _ in 0..100 {
+            if cache.get(&1u64) == Some(&2) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Some(&2), cache.get(&1u64));
+        assert_eq!(1, reloads.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_reload_keeps_deadline() {
+        let held = HeldReloads::default();
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(60),
+            );
+        cache.set_refresh_after_write(
+            Duration::from_secs(60),
+            FlakyLoader(Arc::new(AtomicU64::new(0))),
+        );
+        cache.set_reload_spawner(held.clone());
+
+        // an hour-long deadline, on an entry due for a reload
+        let now = Now::new();
+        let deadline = now.wall + Duration::from_secs(3600);
+        let written_at = now.instant - Duration::from_secs(120);
+        cache.cache.insert(
+            1,
+            ExpiringEntry {
+                inserted_at: written_at,
+                expiration: Expiration::wall(deadline, now),
+                refreshed_at: written_at,
+                ..entry(1)
+            },
+        );
+        assert_eq!(Some(&1), cache.get(&1u64));
+        held.run();
+        assert_eq!(Some(&2), cache.get(&1u64));
+
+        // it still has its deadline rather than the minute timeout
+        let hash_k = cache.cache.hash_k(&1u64);
+        match cache.cache.peek_hashed(hash_k).unwrap().expiration {
+            Expiration::Wall { at, .. } => assert_eq!(deadline, at),
+            _ => panic!("the reload dropped the deadline"),
+        }
+        let expires_at = cache.snapshot()[0].expires_at.unwrap();
+        assert!(expires_at > now.wall + Duration::from_secs(3500));
+    }
+
+    #[test]
+    fn test_reload_loses_to_write() {
+        let reloads = Arc::new(AtomicU64::new(0));
+        let held = HeldReloads::default();
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(3600),
+            );
+        cache.set_refresh_after_write(
+            Duration::from_secs(60),
+            FlakyLoader(reloads.clone()),
+        );
+        cache.set_reload_spawner(held.clone());
+
+        let written_at = Instant::now() - Duration::from_secs(120);
+        cache.cache.insert(
+            1,
+            ExpiringEntry {
+                inserted_at: written_at,
+                refreshed_at: written_at,
+                ..entry(1)
+            },
+        );
+        assert_eq!(Some(&1), cache.get(&1u64));
+
+        // the reload finishes after somebody else wrote the entry, so
+        // what they wrote stays
+        cache.insert(1, 10);
+        held.run();
+        assert_eq!(1, reloads.load(Ordering::SeqCst));
+        assert_eq!(Some(&10), cache.get(&1u64));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_secs(30),
+            );
+        assert_eq!(None, cache.stats());
+        cache.set_record_stats(true);
+        cache.set_refresh_after_write(
+            Duration::from_secs(60),
+            FlakyLoader(Arc::new(AtomicU64::new(0))),
+        );
+        let held = HeldReloads::default();
+        cache.set_reload_spawner(held.clone());
+
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(Some(&3), cache.get(&3u64));
+        assert_eq!(None, cache.get(&1u64));
+
+        // one entry long expired, and one due for a reload
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        for (k, expiration) in [
+            (4, Expiration::Timeout),
+            (
+                5,
+                Expiration::At(
+                    Instant::now() + Duration::from_secs(60),
+                ),
+            ),
+        ] {
+            cache.cache.insert(
+                k,
+                ExpiringEntry {
+                    inserted_at: long_ago,
+                    expiration,
+                    refreshed_at: long_ago,
+                    ..entry(k)
+                },
+            );
+        }
+        assert_eq!(None, cache.get(&4u64));
+        assert_eq!(Some(&5), cache.get(&5u64));
+        held.run();
+        assert_eq!(Some(&6), cache.get(&5u64));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(3, stats.hits);
+        assert_eq!(2, stats.misses);
+        assert_eq!(3, stats.inserts);
+        assert_eq!(1, stats.updates);
+        assert_eq!(1, stats.capacity_evictions);
+        assert_eq!(1, stats.expirations);
+        assert_eq!(1, stats.load_successes);
+        assert_eq!(0, stats.load_failures);
+
+        cache.clear();
+        assert_eq!(1, cache.stats().unwrap().cleared);
+        cache.reset_stats();
+        assert_eq!(Some(Default::default()), cache.stats());
+    }
+
+    #[test]
+    fn test_expired_tail() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                1,
+                Duration::from_secs(30),
+            );
+        cache.set_record_stats(true);
+        let events = cache.subscribe(4, Overflow::DropOldest);
+        let one = cache.key_hash(&1);
+
+        // the only item has expired by the time there's no room for
+        // another, so it goes as an expiry rather than an eviction
+        cache.insert(1, 1);
+        cache.cache.peek_mut_hashed(one).unwrap().inserted_at =
+            Instant::now() - Duration::from_secs(35);
+        cache.insert(2, 2);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(0, stats.capacity_evictions);
+        assert!(stats.eviction_ages.is_empty());
+        assert_eq!(1, stats.expirations);
+        assert_eq!(1, stats.expiry_ages.count());
+        assert!(events.try_recv().is_some());
+        assert_eq!(Some(CacheEvent::Expired(one)), events.try_recv());
+    }
+
+    #[test]
+    fn test_age_histograms() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_millis(50),
+            );
+        cache.set_record_stats(true);
+
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        std::thread::sleep(Duration::from_millis(20));
+        cache.get(&2);
+        cache.insert(3, 3);
+        std::thread::sleep(Duration::from_millis(40));
+        cache.get(&2);
+
+        let stats = cache.stats().unwrap();
+        let at_least = |ages: &crate::AgeHistogram, millis| {
+            ages.quantile(0.0) > Some(Duration::from_millis(millis))
+        };
+
+        // 1 was evicted at 20ms or more, 2 was hit at 20ms or more and
+        // found expired at 60ms or more
+        assert_eq!(1, stats.eviction_ages.count());
+        assert!(at_least(&stats.eviction_ages, 16));
+        assert_eq!(1, stats.hit_ages.count());
+        assert!(at_least(&stats.hit_ages, 16));
+        assert_eq!(1, stats.expiry_ages.count());
+        assert!(at_least(&stats.expiry_ages, 32));
+
+        cache.reset_stats();
+        assert!(cache.stats().unwrap().expiry_ages.is_empty());
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut cache: ExpiringCache<&str, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_secs(60),
+            );
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+
+        let debug = format!("{:?}", cache);
+        assert!(debug.starts_with("ExpiringCache { capacity: 2,"));
+        assert!(debug.ends_with("entries: {\"b\": 2, \"a\": 1} }"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_dump_json() {
+        use crate::JsonDump;
+
+        let mut cache: ExpiringCache<&str, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                2,
+                Duration::from_secs(60),
+            );
+        cache.insert("a", 1);
+
+        let dump = cache.to_json();
+        assert_eq!(1, dump["len"]);
+        assert_eq!("a", dump["entries"][0]["key"]);
+        assert_eq!(1, dump["entries"][0]["value"]);
+        assert!(dump["entries"][0]["age_ms"].is_u64());
+        assert!(
+            dump["entries"][0]["ttl_ms"].as_u64().unwrap() <= 60_000
+        );
+    }
+
+    #[test]
+    fn test_wall_clock_deadlines() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                5,
+                Duration::from_secs(30),
+            );
+
+        let hour = Duration::from_secs(3600);
+        cache.insert_with_deadline(1, 1, SystemTime::now() + hour);
+        cache.insert_with_deadline(
+            2,
+            2,
+            SystemTime::now() - Duration::from_secs(1),
+        );
+        assert_eq!(Some(&1), cache.get(&1u64));
+        assert_eq!(None, cache.get(&2u64));
+
+        // the wall clock jumped forwards past the deadline
+        let now = Now::new();
+        let mut entry = ExpiringEntry {
+            inserted_at: now.instant,
+            expiration: Expiration::Wall {
+                at: now.wall - Duration::from_secs(1),
+                instant: now.instant + hour,
+            },
+            refreshed_at: now.instant,
+            ..entry(3)
+        };
+        assert!(entry.is_expired(cache.timeout, now));
+        assert_eq!(
+            Some(Duration::ZERO),
+            entry.remaining(cache.timeout, now)
+        );
+
+        // the wall clock jumped backwards, but the Instant still passed
+        entry.expiration = Expiration::Wall {
+            at: now.wall + hour,
+            instant: now.instant - Duration::from_secs(1),
+        };
+        assert!(entry.is_expired(cache.timeout, now));
+        assert_eq!(
+            Some(Duration::ZERO),
+            entry.remaining(cache.timeout, now)
+        );
+
+        // both clocks agree on what is left, to the nanosecond
+        entry.expiration = Expiration::Wall {
+            at: now.wall + Duration::from_secs(10),
+            instant: now.instant + hour,
+        };
+        assert!(!entry.is_expired(cache.timeout, now));
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            entry.remaining(cache.timeout, now)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_serde() {
+        let mut cache: ExpiringCache<u64, String> =
+            ExpiringCache::with_capacity_and_timeout(
+                3,
+                Duration::from_secs(60),
+            );
+        cache.insert(1, "one".to_string());
+
+        let snapshot = cache.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: Vec<SnapshotEntry<u64, String>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, parsed);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                3,
+                Duration::from_secs(60),
+            );
+
+        let hour = Duration::from_secs(3600);
+        let deadline = SystemTime::now() + hour;
+        cache.insert(1, 1);
+        cache.insert_with_deadline(2, 2, deadline);
+        cache.insert(3, 3);
+
+        let snapshot = cache.snapshot();
+        assert_eq!(
+            vec![1, 2, 3],
+            snapshot.iter().map(|e| e.key).collect::<Vec<_>>()
+        );
+        let expires_at = snapshot[0].expires_at.unwrap();
+        assert!(
+            expires_at <= SystemTime::now() + Duration::from_secs(60)
+        );
+        assert!(
+            expires_at > SystemTime::now() + Duration::from_secs(50)
+        );
+
+        let mut restored: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                3,
+                Duration::from_secs(60),
+            );
+        let mut snapshot = snapshot;
+        snapshot.push(SnapshotEntry {
+            key: 4,
+            value: 4,
+            expires_at: Some(SystemTime::now() - hour),
+        });
+        snapshot.push(SnapshotEntry {
+            key: 5,
+            value: 5,
+            expires_at: None,
+        });
+        assert_eq!(4, restored.restore(snapshot));
+
+        // 1 was the oldest, so it made room for 5
+        assert_eq!(None, restored.get(&1u64));
+        assert_eq!(Some(&2), restored.get(&2u64));
+        assert_eq!(Some(&3), restored.get(&3u64));
+        assert_eq!(None, restored.get(&4u64));
+        assert_eq!(Some(&5), restored.get(&5u64));
+        assert_eq!(3, restored.len());
+    }
 }
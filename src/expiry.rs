@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+/// Decides how long entries in an [`ExpiringCache`] live based on what
+/// they are, rather than on a single timeout for the whole cache. This
+/// is handy for things like tokens which carry their own expiry inside
+/// of them.
+///
+/// Every callback returns how long the entry has left to live from
+/// `now`, or `None` if it should never expire.
+///
+/// [`ExpiringCache`]: crate::ExpiringCache
+pub trait Expiry<K, V> {
+    /// Called when a key is inserted into the cache which was not
+    /// already there.
+    fn expire_after_create(
+        &self,
+        k: &K,
+        v: &V,
+        now: Instant,
+    ) -> Option<Duration>;
+
+    /// Called when the value of a key which is already in the cache is
+    /// replaced. `remaining` is how long the old value had left. By
+    /// default this treats the new value as though it were freshly
+    /// created.
+    fn expire_after_update(
+        &self,
+        k: &K,
+        v: &V,
+        now: Instant,
+        remaining: Option<Duration>,
+    ) -> Option<Duration> {
+        let _ = remaining;
+        self.expire_after_create(k, v, now)
+    }
+
+    /// Called when an entry is read out of the cache. By default
+    /// reading an entry does not change how long it has left.
+    fn expire_after_read(
+        &self,
+        k: &K,
+        v: &V,
+        now: Instant,
+        remaining: Option<Duration>,
+    ) -> Option<Duration> {
+        let _ = (k, v, now);
+        remaining
+    }
+}
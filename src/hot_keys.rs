@@ -0,0 +1,473 @@
+//! Finds the keys which are looked up the most, using the Space-Saving
+//! algorithm (Metwally et al, ICDT '05) over a sliding window of time.
+//!
+//! The window is split into panes which each keep their own fixed number
+//! of counters, and the oldest pane is thrown away as each new one starts,
+//! so the memory used depends on how many keys are asked for and never on
+//! how many keys the cache holds.
+//!
+//! Lookups may only have a borrowed form of the key, so they are counted
+//! by hash, and the keys themselves are picked up as they're written. Both
+//! are recorded in striped buffers first and counted in batches, so that
+//! threads looking keys up don't queue for the lock one by one.
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::KeyHash, null_hasher::BuildNullHasher,
+    read_buffer::ReadBuffer,
+};
+
+/// How many panes the window is split into. More panes make the window
+/// slide more smoothly, at the cost of more counters.
+const PANES: usize = 4;
+
+/// How many counters each pane keeps for each key asked for. Space-Saving
+/// is only accurate for keys which are more common than one in the number
+/// of counters, so a little slack goes a long way.
+const COUNTERS_PER_KEY: usize = 8;
+
+/// How many of the keys most recently written are kept for naming hot
+/// keys, for each counter.
+const NAMES_PER_COUNTER: usize = PANES + 1;
+
+/// A key which has been looked up a lot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey<K> {
+    /// The key itself, if it was written recently enough, or since it
+    /// became hot, to be remembered.
+    pub key: Option<K>,
+    /// The hash of the key, as given by [`Cache::key_hash`].
+    ///
+    /// [`Cache::key_hash`]: crate::Cache::key_hash
+    pub hash: KeyHash,
+    /// About how many times it was looked up within the window. This
+    /// overcounts by at most `error`, and only undercounts by the few
+    /// lookups which were dropped because other threads were recording
+    /// theirs at the same moment.
+    pub count: u64,
+    /// How much `count` may be over by.
+    pub error: u64,
+}
+
+/// The Space-Saving counters for one pane of the window. When a key turns
+/// up which isn't counted and every counter is taken, it takes over the
+/// smallest counter, inheriting its count as its error.
+struct Pane {
+    started: Instant,
+    counters: HashMap<KeyHash, (u64, u64), BuildNullHasher>,
+    by_count: BTreeSet<(u64, KeyHash)>,
+    capacity: usize,
+}
+
+impl Pane {
+    fn new(started: Instant, capacity: usize) -> Self {
+        Pane {
+            started,
+            counters: HashMap::with_hasher(BuildNullHasher),
+            by_count: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    fn observe(&mut self, hash_k: KeyHash) {
+        if let Some((count, _)) = self.counters.get_mut(&hash_k) {
+            self.by_count.remove(&(*count, hash_k));
+            *count += 1;
+            self.by_count.insert((*count, hash_k));
+            return;
+        }
+
+        let (count, error) = if self.counters.len() < self.capacity {
+            (1, 0)
+        } else {
+            // a full pane always has a smallest counter
+            let (min, evicted) = match self.by_count.pop_first() {
+                Some(smallest) => smallest,
+                None => return,
+            };
+            self.counters.remove(&evicted);
+            (min + 1, min)
+        };
+
+        self.counters.insert(hash_k, (count, error));
+        self.by_count.insert((count, hash_k));
+    }
+
+    /// The most a key which isn't counted here could have been seen.
+    fn uncounted(&self) -> u64 {
+        match self.counters.len() < self.capacity {
+            true => 0,
+            false => self.by_count.first().map_or(0, |(min, _)| *min),
+        }
+    }
+}
+
+struct Window<K> {
+    panes: VecDeque<Pane>,
+    pane_length: Duration,
+    capacity: usize,
+    names: Names<K>,
+}
+
+impl<K> Window<K> {
+    fn is_counted(&self, hash_k: KeyHash) -> bool {
+        self.panes
+            .iter()
+            .any(|pane| pane.counters.contains_key(&hash_k))
+    }
+
+    /// Starts new panes as time goes by, forgetting the oldest.
+    fn advance(&mut self, now: Instant) {
+        let current = match self.panes.back() {
+            Some(pane) => pane.started,
+            None => {
+                self.panes.push_back(Pane::new(now, self.capacity));
+                return;
+            }
+        };
+
+        let elapsed = now.saturating_duration_since(current);
+        if elapsed < self.pane_length {
+            return;
+        }
+
+        // after a long quiet spell nothing left is in the window
+        if elapsed >= self.pane_length * PANES as u32 {
+            self.panes.clear();
+        }
+
+        self.panes.push_back(Pane::new(now, self.capacity));
+        while self.panes.len() > PANES {
+            self.panes.pop_front();
+        }
+    }
+
+    /// Remembers the key with the given hash, forgetting the key written
+    /// longest ago which isn't being counted if there are too many.
+    fn name(&mut self, hash_k: KeyHash, k: K) {
+        let written = self.names.writes;
+        self.names.writes += 1;
+        self.names.keys.insert(hash_k, (k, written));
+        self.names.order.push_back((hash_k, written));
+
+        // there are more names kept than counters, so something which
+        // isn't being counted always turns up
+        let limit = self.capacity * NAMES_PER_COUNTER;
+        while self.names.order.len() > limit {
+            let (hash_k, written) = match self.names.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            match self.names.keys.get(&hash_k) {
+                Some((_, w)) if *w == written => {
+                    if self.is_counted(hash_k) {
+                        self.names.order.push_back((hash_k, written));
+                    } else {
+                        self.names.keys.remove(&hash_k);
+                    }
+                }
+                // written again since, so there's a later entry
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Keys recently written, by their hash, along with the order they were
+/// written in so the oldest can be forgotten. Each is numbered by when it
+/// was written, so a key written again isn't forgotten by its first
+/// write.
+struct Names<K> {
+    keys: HashMap<KeyHash, (K, u64), BuildNullHasher>,
+    order: VecDeque<(KeyHash, u64)>,
+    writes: u64,
+}
+
+/// Tracks the keys looked up most often in a cache over a sliding window
+/// of time. Clones share their counts, so one can be attached to a cache
+/// with [`LruCache::attach_hot_keys`] or [`SharedCache::attach_hot_keys`]
+/// while another is kept to read the hot keys from.
+///
+/// A copy of each key written to the cache is kept for as long as it's
+/// among the most recently written, or is being counted, so that the hot
+/// keys can be named rather than only hashed.
+///
+/// Lookups and writes are buffered a thread at a time and counted once a
+/// buffer fills up, or when the hot keys are asked for. Like the reads of
+/// a SharedCache with buffered reads, a few are dropped when threads
+/// sharing a buffer record at the same moment, rather than either waiting.
+///
+/// [`LruCache::attach_hot_keys`]: crate::LruCache::attach_hot_keys
+/// [`SharedCache::attach_hot_keys`]: crate::SharedCache::attach_hot_keys
+pub struct HotKeys<K> {
+    k: usize,
+    window: Arc<Mutex<Window<K>>>,
+    lookups: Arc<ReadBuffer>,
+    writes: Arc<ReadBuffer<(KeyHash, K)>>,
+    clone_key: fn(&K) -> K,
+}
+
+impl<K> Clone for HotKeys<K> {
+    fn clone(&self) -> Self {
+        HotKeys {
+            k: self.k,
+            window: self.window.clone(),
+            lookups: self.lookups.clone(),
+            writes: self.writes.clone(),
+            clone_key: self.clone_key,
+        }
+    }
+}
+
+impl<K: Clone> HotKeys<K> {
+    /// Makes a tracker for the `k` hottest keys over the last `window` of
+    /// time. Panics if `k` is zero.
+    pub fn new(k: usize, window: Duration) -> Self {
+        assert!(k > 0, "HotKeys needs to track at least one key");
+
+        HotKeys {
+            k,
+            window: Arc::new(Mutex::new(Window {
+                panes: VecDeque::with_capacity(PANES + 1),
+                pane_length: window / PANES as u32,
+                capacity: k * COUNTERS_PER_KEY,
+                names: Names {
+                    keys: HashMap::with_hasher(BuildNullHasher),
+                    order: VecDeque::new(),
+                    writes: 0,
+                },
+            })),
+            lookups: Arc::new(ReadBuffer::new()),
+            writes: Arc::new(ReadBuffer::new()),
+            clone_key: K::clone,
+        }
+    }
+}
+
+impl<K> HotKeys<K> {
+    /// Notes a lookup of the key with the given hash.
+    pub fn observe(&self, hash_k: KeyHash) {
+        if self.lookups.record(hash_k) {
+            self.flush(&mut self.lock());
+        }
+    }
+
+    /// Notes that the key with the given hash was written, so that it can
+    /// be named should it turn out to be hot.
+    pub(crate) fn written(&self, hash_k: KeyHash, k: &K) {
+        if self.writes.record_with(|| (hash_k, (self.clone_key)(k))) {
+            self.flush(&mut self.lock());
+        }
+    }
+
+    /// The hottest keys in the window, hottest first. There may be fewer
+    /// than were asked for if not many keys have been looked up.
+    pub fn top(&self) -> Vec<HotKey<K>> {
+        let mut window = self.lock();
+        self.flush(&mut window);
+        window.advance(Instant::now());
+
+        let mut totals: HashMap<KeyHash, HotKey<K>, BuildNullHasher> =
+            HashMap::with_hasher(BuildNullHasher);
+
+        for pane in &window.panes {
+            for hash_k in pane.counters.keys() {
+                totals.entry(*hash_k).or_insert_with(|| HotKey {
+                    key: None,
+                    hash: *hash_k,
+                    count: 0,
+                    error: 0,
+                });
+            }
+        }
+
+        // a key missing from a pane may still have been seen there, as
+        // often as that pane's smallest counter, so count it as if it was
+        // to keep from undercounting
+        for pane in &window.panes {
+            let uncounted = pane.uncounted();
+            for (hash_k, total) in totals.iter_mut() {
+                match pane.counters.get(hash_k) {
+                    Some((count, error)) => {
+                        total.count += count;
+                        total.error += error;
+                    }
+                    None => {
+                        total.count += uncounted;
+                        total.error += uncounted;
+                    }
+                }
+            }
+        }
+
+        let mut top: Vec<HotKey<K>> = totals.into_values().collect();
+        top.sort_unstable_by(|a, b| {
+            b.count.cmp(&a.count).then(a.hash.cmp(&b.hash))
+        });
+        top.truncate(self.k);
+
+        for hot in &mut top {
+            hot.key = window
+                .names
+                .keys
+                .get(&hot.hash)
+                .map(|(k, _)| (self.clone_key)(k));
+        }
+
+        top
+    }
+
+    /// Counts the buffered lookups and names the buffered writes, as of
+    /// now.
+    fn flush(&self, window: &mut Window<K>) {
+        window.advance(Instant::now());
+
+        // lookups first, so that keys they make hot aren't forgotten by
+        // the names which follow
+        if let Some(pane) = window.panes.back_mut() {
+            self.lookups.drain(|hash_k| pane.observe(hash_k));
+        }
+        self.writes.drain(|(hash_k, k)| window.name(hash_k, k));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Window<K>> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::{HotKey, HotKeys, NAMES_PER_COUNTER};
+    use crate::{Cache, LruCache};
+
+    #[test]
+    fn test_top_keys() {
+        let hot_keys: HotKeys<u64> =
+            HotKeys::new(2, Duration::from_secs(60));
+
+        // plenty of cold keys, each seen once, around two hot ones
+        for cold in 100..1100 {
+            hot_keys.observe(cold);
+            if cold % 4 == 0 {
+                hot_keys.observe(1);
+            }
+            if cold % 10 == 0 {
+                hot_keys.observe(2);
+            }
+        }
+
+        let top = hot_keys.top();
+        assert_eq!(
+            vec![1, 2],
+            top.iter().map(|h| h.hash).collect::<Vec<_>>()
+        );
+        for (hot, actual) in top.iter().zip([250, 100]) {
+            assert!(hot.count >= actual);
+            assert!(hot.count - hot.error <= actual);
+        }
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let hot_keys = HotKeys::new(1, Duration::from_millis(40));
+
+        hot_keys.written(1, &1);
+        for _ in 0..10 {
+            hot_keys.observe(1);
+        }
+        assert_eq!(
+            vec![HotKey {
+                key: Some(1),
+                hash: 1,
+                count: 10,
+                error: 0
+            }],
+            hot_keys.top()
+        );
+
+        thread::sleep(Duration::from_millis(60));
+        hot_keys.observe(2);
+        assert_eq!(2, hot_keys.top()[0].hash);
+    }
+
+    #[test]
+    fn test_buffered() {
+        let hot_keys = HotKeys::new(1, Duration::from_secs(60));
+
+        // lookups and writes are recorded without waiting on the lock
+        let window = hot_keys.lock();
+        let (done, finished) = mpsc::channel();
+        let thread_hot_keys = hot_keys.clone();
+        thread::spawn(move || {
+            thread_hot_keys.written(1, &1);
+            for _ in 0..10 {
+                thread_hot_keys.observe(1);
+            }
+            done.send(()).unwrap();
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+        drop(window);
+
+        // and counted once they're asked for
+        assert_eq!(
+            vec![HotKey {
+                key: Some(1),
+                hash: 1,
+                count: 10,
+                error: 0
+            }],
+            hot_keys.top()
+        );
+    }
+
+    #[test]
+    fn test_attached_to_cache() {
+        let hot_keys = HotKeys::new(1, Duration::from_secs(60));
+        let mut cache: LruCache<&str, u64> =
+            LruCache::with_capacity(10);
+        cache.attach_hot_keys(hot_keys.clone());
+
+        cache.insert("hot", 1);
+        for _ in 0..3 {
+            cache.get("hot");
+            cache.get("cold");
+        }
+        cache.get("hot");
+
+        let top = hot_keys.top();
+        assert_eq!(cache.key_hash("hot"), top[0].hash);
+        assert_eq!(Some("hot"), top[0].key);
+        assert!(cache.detach_hot_keys().is_some());
+    }
+
+    #[test]
+    fn test_names_are_bounded() {
+        let hot_keys = HotKeys::new(1, Duration::from_secs(60));
+        hot_keys.written(0, &0);
+        for _ in 0..3 {
+            hot_keys.observe(0);
+        }
+
+        // plenty of keys written since, which are never looked up
+        for k in 1..1000 {
+            hot_keys.written(k, &k);
+        }
+
+        let window = hot_keys.lock();
+        let limit = window.capacity * NAMES_PER_COUNTER;
+        assert!(window.names.keys.len() <= limit);
+        assert!(window.names.order.len() <= limit);
+        drop(window);
+
+        // and the hot one is remembered all the same
+        assert_eq!(Some(0), hot_keys.top()[0].key);
+    }
+}
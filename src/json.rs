@@ -0,0 +1,60 @@
+//! Dumps what is in a cache as JSON, for diagnostics endpoints and bug
+//! reports.
+
+use serde_json::{json, Value};
+use std::time::Instant;
+
+use crate::{cache::KeyHash, metadata::EntryInfo};
+
+/// A cache whose contents can be dumped as JSON.
+///
+/// The dump has the cache's capacity and length along with every entry,
+/// from the most to the least recently used. Each entry has the hash of
+/// its key as a hex string, its value, and whatever is known of its
+/// metadata, with times given in milliseconds as ages from now. Metadata
+/// which isn't known is null. A value which can't be turned into JSON is
+/// replaced by an object with the reason in an `error` field.
+pub trait JsonDump {
+    /// The contents of the cache as a JSON value, to be put into a bigger
+    /// document.
+    fn to_json(&self) -> Value;
+
+    /// The contents of the cache as a string of JSON.
+    fn dump_json(&self) -> String {
+        self.to_json().to_string()
+    }
+}
+
+/// The JSON for a single entry, which callers can add more to.
+pub(crate) fn entry_json<V>(
+    hash_k: KeyHash,
+    value: &V,
+    info: EntryInfo,
+    now: Instant,
+) -> serde_json::Map<String, Value>
+where
+    V: serde::Serialize,
+{
+    let millis_since = |then: Instant| {
+        now.saturating_duration_since(then).as_millis() as u64
+    };
+
+    let entry = json!({
+        "hash": format!("{:#018x}", hash_k),
+        "value": serde_json::to_value(value)
+            .unwrap_or_else(|e| json!({ "error": e.to_string() })),
+        "weight": info.weight,
+        "age_ms": info.created_at.map(millis_since),
+        "idle_ms": info
+            .last_accessed_at
+            .or(info.created_at)
+            .map(millis_since),
+        "access_count": info.access_count,
+        "ttl_ms": info.time_to_live.map(|ttl| ttl.as_millis() as u64),
+    });
+
+    match entry {
+        Value::Object(entry) => entry,
+        _ => unreachable!("json! of an object is an object"),
+    }
+}
@@ -1,15 +1,67 @@
 //! A quick and dirty implementation of an LRU cache.
 
+#[cfg(feature = "shared_cache")]
+mod arc_cache;
+#[cfg(feature = "async")]
+mod async_cache;
 mod cache;
+mod events;
 mod expiring_cache;
+mod expiry;
+mod hot_keys;
+#[cfg(feature = "serde")]
+mod json;
 mod linked_map;
+mod loader;
 mod lru_cache;
+mod metadata;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mrc;
 mod null_hasher;
+mod read_buffer;
+#[cfg(feature = "shared_cache")]
+mod reaper;
+mod rng;
+#[cfg(feature = "shared_cache")]
+mod sharded_cache;
 #[cfg(feature = "shared_cache")]
 mod shared_cache;
+#[cfg(any(feature = "shared_cache", feature = "async"))]
+mod single_flight;
+mod stats;
+#[cfg(feature = "shared_cache")]
+mod tiered_cache;
+#[cfg(feature = "tracing")]
+mod trace;
 
-pub use cache::Cache;
-pub use expiring_cache::ExpiringCache;
+#[cfg(feature = "shared_cache")]
+pub use arc_cache::ArcCache;
+#[cfg(feature = "async")]
+pub use async_cache::AsyncCache;
+pub use cache::{Cache, ConcurrentCache, KeyHash};
+pub use events::{CacheEvent, CacheEvents, EventSource, Overflow};
+pub use expiring_cache::{ExpiringCache, SnapshotEntry};
+pub use expiry::Expiry;
+pub use hot_keys::{HotKey, HotKeys};
+#[cfg(feature = "serde")]
+pub use json::JsonDump;
+pub use loader::{Loader, Reload, Spawner, ThreadSpawner};
 pub use lru_cache::LruCache;
+pub use metadata::EntryInfo;
+#[cfg(feature = "metrics")]
+pub use metrics::{
+    CacheMetrics, ExpositionFormat, MeteredCache, MetricsRegistry,
+};
+pub use mrc::{MissRatioCurve, MrcEstimator};
+#[cfg(feature = "shared_cache")]
+pub use reaper::Reaper;
+#[cfg(feature = "shared_cache")]
+pub use sharded_cache::ShardedCache;
+#[cfg(feature = "shared_cache")]
+pub use shared_cache::{SharedCache, WouldBlock};
+pub use stats::{AgeHistogram, CacheStats};
 #[cfg(feature = "shared_cache")]
-pub use shared_cache::SharedCache;
+pub use tiered_cache::TieredCache;
+#[cfg(feature = "tracing")]
+pub use trace::{CacheTracer, TraceLevels, Traceable};
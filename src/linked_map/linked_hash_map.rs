@@ -1,8 +1,9 @@
-use super::{KeyHash, LinkedMapNode};
+use super::{DebugHash, KeyHash, LinkedMapNode};
 use crate::null_hasher::BuildNullHasher;
 use std::{
     collections::{hash_map::RandomState, HashMap},
-    hash::{BuildHasher, Hash, Hasher},
+    fmt,
+    hash::{BuildHasher, Hash},
     marker::PhantomData,
 };
 
@@ -20,6 +21,7 @@ where
     kpd: PhantomData<K>,
 }
 
+/// Walks the map from the most to the least recently inserted node.
 pub struct LinkedHashMapIter<'a, K, V, S>
 where
     K: Eq + Hash,
@@ -29,6 +31,7 @@ where
     inner_map: &'a LinkedHashMap<K, V, S>,
 }
 
+#[cfg(test)]
 pub struct ReverseLinkedHashMapIter<'z, K, V, S>
 where
     K: Eq + Hash,
@@ -72,7 +75,6 @@ where
         }
     }
 
-    #[cfg(test)]
     pub fn iter(&self) -> LinkedHashMapIter<'_, K, V, S> {
         LinkedHashMapIter {
             idx: self.head,
@@ -138,11 +140,11 @@ where
         self.interior_map.contains_key(&self.k_hash(k))
     }
 
-    pub fn get<Q>(&self, k: &Q) -> Option<&LinkedMapNode<V>>
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
     where
         Q: Hash + Eq,
     {
-        self.interior_map.get(&self.k_hash(&k))
+        self.interior_map.get(&self.k_hash(&k)).map(|n| &n.value)
     }
 
     pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
@@ -154,11 +156,30 @@ where
             .map(|n| &mut n.value)
     }
 
+    /// The hash of the key of the least recently inserted node, which is
+    /// the next to go in [`LinkedHashMap::remove_tail`].
+    pub fn tail_hash(&self) -> Option<KeyHash> {
+        self.tail
+    }
+
     pub fn remove_tail(&mut self) -> Option<V> {
         if let Some(tail_k) = self.tail {
             if let Some(tail_node) = self.interior_map.remove(&tail_k) {
                 self.tail = tail_node.left;
 
+                // the new tail must not point at the node we just
+                // removed, and removing the last node empties the list
+                match self.tail {
+                    Some(left_k) => {
+                        if let Some(left_node) =
+                            self.interior_map.get_mut(&left_k)
+                        {
+                            left_node.right = None;
+                        }
+                    }
+                    None => self.head = None,
+                }
+
                 #[cfg(test)]
                 self.continuity_test();
 
@@ -245,12 +266,11 @@ where
     where
         Q: Hash + Eq,
     {
-        let mut h = self.hash_builder.build_hasher();
-        k.hash(&mut h);
-        h.finish()
+        self.hash_builder.hash_one(k)
     }
 
     #[cfg(test)]
+    #[allow(clippy::assign_op_pattern)]
     fn continuity_test(&self) {
         let mut count = 0;
 
@@ -275,20 +295,38 @@ where
     }
 }
 
+/// Shows the map from the most to the least recently inserted node.
+impl<K, V, S> fmt::Debug for LinkedHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter().map(|(k_hash, v)| (DebugHash(k_hash), v)),
+            )
+            .finish()
+    }
+}
+
 impl<'a, K, V, S> Iterator for LinkedHashMapIter<'a, K, V, S>
 where
     K: Hash + Eq,
     S: BuildHasher,
 {
     // TODO: Can this be (K, V) like a real map? We'd have to commit to
-    // storing the K as well!
-    type Item = &'a V;
+    // storing the K as well! Until then the hash is the best we've got.
+    type Item = (KeyHash, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(k_hash) = self.idx {
-            if let Some(k_value) = self.inner_map.get(&k_hash) {
+            if let Some(k_value) =
+                self.inner_map.interior_map.get(&k_hash)
+            {
                 self.idx = k_value.right;
-                return Some(&k_value.value);
+                return Some((k_hash, &k_value.value));
             }
         }
 
@@ -296,6 +334,7 @@ where
     }
 }
 
+#[cfg(test)]
 impl<'z, K, V, S> Iterator for ReverseLinkedHashMapIter<'z, K, V, S>
 where
     K: Hash + Eq,
@@ -305,7 +344,9 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(k_hash) = self.idx {
-            if let Some(k_value) = self.inner_map.get(&k_hash) {
+            if let Some(k_value) =
+                self.inner_map.interior_map.get(&k_hash)
+            {
                 self.idx = k_value.left;
                 return Some(&k_value.value);
             }
@@ -316,10 +357,43 @@ where
 }
 
 #[cfg(test)]
+#[allow(clippy::redundant_pattern_matching)]
 mod tests {
     use super::LinkedHashMap;
     use crate::null_hasher::BuildNullHasher;
 
+    /// This test removes the tail until the map is empty and reinserts
+    /// the removed keys, which used to leave the new tail pointing at the
+    /// node removed before it, and the head pointing at nothing.
+    #[test]
+    fn test_linked_hash_map_remove_tail() {
+        let mut linked_hash_map: LinkedHashMap<
+            u64,
+            u64,
+            BuildNullHasher,
+        > = LinkedHashMap::with_capacity_and_hash_builder(
+            5,
+            BuildNullHasher,
+        );
+
+        assert_eq!(None, linked_hash_map.insert(0, 0));
+        assert_eq!(None, linked_hash_map.insert(1, 1));
+        assert_eq!(None, linked_hash_map.insert(2, 2));
+
+        assert_eq!(Some(0), linked_hash_map.remove_tail());
+        assert_eq!(None, linked_hash_map.insert(0, 0));
+        assert_eq!(3, linked_hash_map.iter().count());
+
+        assert_eq!(Some(1), linked_hash_map.remove_tail());
+        assert_eq!(Some(2), linked_hash_map.remove_tail());
+        assert_eq!(Some(0), linked_hash_map.remove_tail());
+        assert_eq!(None, linked_hash_map.remove_tail());
+
+        assert_eq!(None, linked_hash_map.insert(3, 3));
+        assert_eq!(1, linked_hash_map.iter().count());
+        assert_eq!(1, linked_hash_map.reverse_iter().count());
+    }
+
     /// This test adds three elements to the map and removes the middle,
     /// then the head node, to ensure the structure remains consistent
     /// throughout.
@@ -10,6 +10,18 @@ pub use linked_hash_map::LinkedHashMap;
 
 pub type KeyHash = u64;
 
+/// Shows a key hash the way [`Debug`] output of the caches does, since
+/// they hold on to the hashes of their keys rather than the keys.
+///
+/// [`Debug`]: std::fmt::Debug
+pub struct DebugHash(pub KeyHash);
+
+impl std::fmt::Debug for DebugHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct LinkedMapNode<V> {
     left: Option<KeyHash>,
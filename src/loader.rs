@@ -0,0 +1,98 @@
+use std::{error::Error, thread};
+
+/// Reloads values for an [`ExpiringCache`] which has been told to
+/// refresh its entries some time after they were written.
+///
+/// [`ExpiringCache`]: crate::ExpiringCache
+pub trait Loader<K, V> {
+    /// Loads a fresh value for `k`, whose current value is `old`.
+    /// Returning an error leaves the old value in the cache.
+    fn reload(
+        &self,
+        k: &K,
+        old: &V,
+    ) -> Result<V, Box<dyn Error + Send + Sync>>;
+}
+
+/// Runs the reloads an [`ExpiringCache`] wants done somewhere other than
+/// on the thread whose get asked for them, so that nobody waits on the
+/// loader. Running them in place works, but holds up that get and, behind
+/// a SharedCache, everyone waiting on its lock.
+///
+/// [`ExpiringCache`]: crate::ExpiringCache
+pub trait Spawner {
+    /// Runs `reload` on some other thread or task, soon.
+    fn spawn(&self, reload: Reload);
+}
+
+/// Runs each reload on a thread of its own, which is what an
+/// [`ExpiringCache`] does unless it is given some other [`Spawner`].
+///
+/// [`ExpiringCache`]: crate::ExpiringCache
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawner;
+
+impl Spawner for ThreadSpawner {
+    fn spawn(&self, reload: Reload) {
+        thread::spawn(move || reload.run());
+    }
+}
+
+/// A reload a cache wants done, handed to its [`Spawner`] so that the
+/// loader can run without the cache being borrowed, or locked, for however
+/// long it takes.
+///
+/// Once it has run, the new value goes into the cache the next time
+/// something is gotten from or written to it. Until then the entry keeps
+/// its old value and isn't reloaded again, so a reload which is dropped
+/// without being run leaves its entry alone until it is next written.
+pub struct Reload {
+    load: Box<dyn FnOnce() + Send>,
+    #[cfg(feature = "tracing")]
+    pub(crate) span: Option<tracing::Span>,
+}
+
+impl Reload {
+    pub(crate) fn new<F>(load: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Reload {
+            load: Box::new(load),
+            #[cfg(feature = "tracing")]
+            span: None,
+        }
+    }
+
+    /// Runs the loader.
+    pub fn run(self) {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.map(|span| span.entered());
+
+        (self.load)()
+    }
+}
+
+/// What a [`Reload`] loaded, ready to be put back into the cache.
+pub(crate) struct Reloaded<C> {
+    finish: Box<dyn FnOnce(&mut C) + Send>,
+}
+
+impl<C> Reloaded<C> {
+    pub(crate) fn new<F>(finish: F) -> Self
+    where
+        F: FnOnce(&mut C) + Send + 'static,
+    {
+        Reloaded {
+            finish: Box::new(finish),
+        }
+    }
+
+    /// Puts the reloaded value into the cache, unless its entry was
+    /// written or removed while the loader was running, in which case
+    /// what's there now is left alone. A failed reload keeps the old
+    /// value.
+    pub(crate) fn apply(self, cache: &mut C) {
+        (self.finish)(cache)
+    }
+}
@@ -1,21 +1,33 @@
 use std::{
+    borrow::Borrow,
     collections::hash_map::RandomState,
-    hash::{BuildHasher, Hash, Hasher},
+    fmt,
+    hash::{BuildHasher, Hash},
     marker::PhantomData,
 };
 
-use crate::linked_map::LinkedHashMap;
-use crate::{cache::Cache, null_hasher::BuildNullHasher};
+use crate::linked_map::{DebugHash, LinkedHashMap};
+#[cfg(feature = "tracing")]
+use crate::trace::{CacheTracer, EvictionCause, Traceable};
+use crate::{
+    cache::{Cache, KeyHash},
+    events::{
+        CacheEvent, CacheEvents, EventSink, EventSource, Overflow,
+    },
+    hot_keys::HotKeys,
+    metadata::{EntryInfo, MetadataStore},
+    mrc::MrcEstimator,
+    null_hasher::BuildNullHasher,
+    stats::{CacheStats, StatsRecorder},
+};
 
-/// Stores an element in the cache with the handle to its position in 
+/// Stores an element in the cache with the handle to its position in
 /// the eviction queue.
 struct StorageNode<V> {
     /// The value being stored.
     value: V,
 }
 
-type KeyHash = u64;
-
 /// A mostly horrible implementation of an LRU Cache, based on a trivial
 /// implementation of a Linked Hash Map.
 pub struct LruCache<K, V, S = RandomState>
@@ -31,6 +43,13 @@ where
     // that we are incapable of printing back out the contents of the
     // cache except by hash, which is kind of silly.
     kpd: PhantomData<K>,
+    stats: StatsRecorder,
+    events: EventSink,
+    mrc: Option<MrcEstimator>,
+    hot_keys: Option<HotKeys<K>>,
+    metadata: MetadataStore,
+    #[cfg(feature = "tracing")]
+    tracer: Option<CacheTracer>,
 }
 
 impl<K, V> LruCache<K, V, RandomState>
@@ -65,75 +84,414 @@ where
             capacity,
             hash_builder,
             kpd: PhantomData,
+            stats: StatsRecorder::default(),
+            events: EventSink::default(),
+            mrc: None,
+            hot_keys: None,
+            metadata: MetadataStore::default(),
+            #[cfg(feature = "tracing")]
+            tracer: None,
         }
     }
 
-    fn hash_k<Q>(&self, k: &Q) -> KeyHash
+    /// The most items the cache holds before it starts evicting.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Starts or stops keeping [`CacheStats`] for this cache. They are off
+    /// to begin with, and turning them off throws away what was recorded.
+    /// While they're on the cache keeps the time each item was written,
+    /// for the age histograms, so items written since then have ages.
+    pub fn set_record_stats(&mut self, record: bool) {
+        self.stats.set_recording(record);
+        self.metadata.set_timing(record);
+    }
+
+    /// What the cache has been up to since it started recording stats, or
+    /// None if it isn't recording them.
+    pub fn stats(&self) -> Option<CacheStats> {
+        self.stats.snapshot()
+    }
+
+    /// Sets every stat back to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// Starts or stops keeping track of when each item was written and
+    /// how it has been gotten since, for [`Cache::entry_info`]. It is off
+    /// to begin with, and turning it off throws away what was tracked.
+    pub fn set_track_metadata(&mut self, track: bool) {
+        self.metadata.set_tracking(track);
+    }
+
+    /// Has an [`MrcEstimator`] watch every key looked up in the cache from
+    /// now on, replacing any which was already attached. Only gets count,
+    /// so reads through a SharedCache with buffered reads, which peek
+    /// rather than get, go unseen.
+    pub fn attach_mrc_estimator(&mut self, estimator: MrcEstimator) {
+        self.mrc = Some(estimator);
+    }
+
+    /// Stops the attached [`MrcEstimator`] watching the cache, and hands it
+    /// back.
+    pub fn detach_mrc_estimator(&mut self) -> Option<MrcEstimator> {
+        self.mrc.take()
+    }
+
+    /// Has a [`HotKeys`] tracker count every key looked up in the cache
+    /// from now on, replacing any which was already attached.
+    pub fn attach_hot_keys(&mut self, hot_keys: HotKeys<K>) {
+        self.hot_keys = Some(hot_keys);
+    }
+
+    /// Stops the attached [`HotKeys`] tracker counting lookups, and hands
+    /// it back.
+    pub fn detach_hot_keys(&mut self) -> Option<HotKeys<K>> {
+        self.hot_keys.take()
+    }
+
+    pub(crate) fn hash_k<Q>(&self, k: &Q) -> KeyHash
     where
-        Q: Hash + Eq,
+        Q: Hash + ?Sized,
     {
-        let mut h = self.hash_builder.build_hasher();
-        k.hash(&mut h);
-        h.finish()
+        self.hash_builder.hash_one(k)
+    }
+
+    /// Inserts a value under a key which has already been hashed with
+    /// [`LruCache::hash_k`], for wrappers which need to hold on to the
+    /// key themselves.
+    pub(crate) fn insert_hashed(
+        &mut self,
+        hash_k: KeyHash,
+        v: V,
+    ) -> Option<V> {
+        let old_v = self.storage.remove(&hash_k);
+
+        if self.len() + 1 > self.capacity {
+            if let Some(tail_k) = self.storage.tail_hash() {
+                let written = self.metadata.written_at(tail_k);
+                self.storage.remove_tail();
+                self.metadata.removed(tail_k);
+                self.stats.record(|s| {
+                    s.capacity_evictions += 1;
+                    if let Some(written) = written {
+                        s.eviction_ages.record(written.elapsed());
+                    }
+                });
+                self.events.emit(CacheEvent::Evicted(tail_k));
+                #[cfg(feature = "tracing")]
+                if let Some(tracer) = &self.tracer {
+                    tracer.evicted(tail_k, EvictionCause::Capacity);
+                }
+            }
+        }
+
+        self.storage.insert(hash_k, StorageNode { value: v });
+        self.metadata.written(hash_k);
+        self.stats.record(|s| match old_v {
+            Some(_) => s.updates += 1,
+            None => s.inserts += 1,
+        });
+        self.events.emit(match old_v {
+            Some(_) => CacheEvent::Updated(hash_k),
+            None => CacheEvent::Inserted(hash_k),
+        });
+
+        old_v.map(|v| v.value)
+    }
+
+    /// Gets a value by a key which has already been hashed, making it
+    /// the youngest item in the cache.
+    pub(crate) fn get_mut_hashed(
+        &mut self,
+        hash_k: KeyHash,
+    ) -> Option<&mut V> {
+        match self.storage.remove(&hash_k) {
+            Some(v) => {
+                self.storage.insert(hash_k, v);
+                self.metadata.accessed(hash_k);
+                self.storage.get_mut(&hash_k).map(|v| &mut v.value)
+            }
+            None => None,
+        }
+    }
+
+    /// Gets a value by a key which has already been hashed without
+    /// changing its place in the eviction queue.
+    pub(crate) fn peek_hashed(&self, hash_k: KeyHash) -> Option<&V> {
+        self.storage.get(&hash_k).map(|v| &v.value)
+    }
+
+    /// Gets a value by a key which has already been hashed without
+    /// changing its place in the eviction queue.
+    pub(crate) fn peek_mut_hashed(
+        &mut self,
+        hash_k: KeyHash,
+    ) -> Option<&mut V> {
+        self.storage.get_mut(&hash_k).map(|v| &mut v.value)
+    }
+
+    /// The hash of the oldest item's key, which is the next to be evicted.
+    pub(crate) fn oldest_hash(&self) -> Option<KeyHash> {
+        self.storage.tail_hash()
+    }
+
+    /// Walks the cache from the youngest to the oldest item, along with
+    /// the hash of each item's key.
+    pub(crate) fn iter_hashed(
+        &self,
+    ) -> impl Iterator<Item = (KeyHash, &V)> {
+        self.storage.iter().map(|(hash_k, n)| (hash_k, &n.value))
+    }
+
+    /// Removes a value by a key which has already been hashed.
+    pub(crate) fn remove_hashed(
+        &mut self,
+        hash_k: KeyHash,
+    ) -> Option<V> {
+        self.metadata.removed(hash_k);
+        self.storage.remove(&hash_k).map(|n| n.value)
+    }
+
+    /// What is known about the item under a key which has already been
+    /// hashed, if there is one.
+    pub(crate) fn entry_info_hashed(
+        &self,
+        hash_k: KeyHash,
+    ) -> Option<EntryInfo> {
+        self.peek_hashed(hash_k).map(|_| self.metadata.info(hash_k))
     }
 }
 
-impl<K, V, S> Cache<K, V> for LruCache<K, V, S>
+impl<K, V, S> Cache for LruCache<K, V, S>
 where
     K: Eq + Hash,
     S: BuildHasher,
 {
+    type Key = K;
+    type Value = V;
+
     fn insert(&mut self, k: K, v: V) -> Option<V> {
         let hash_k = self.hash_k(&k);
 
-        let old_v = self.storage.remove(&k);
-
-        if self.len() + 1 > self.capacity {
-            self.storage.remove_tail();
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.written(hash_k, &k);
         }
 
-        self.storage.insert(hash_k, StorageNode { value: v });
-
-        old_v.map(|v| v.value)
+        self.insert_hashed(hash_k, v)
     }
 
     fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
     where
-        Q: Hash + Eq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
         let hash_k = self.hash_k(k);
 
-        match self.storage.remove(&hash_k) {
-            Some(v) => {
-                self.storage.insert(hash_k, v);
-                self.storage.get_mut(&hash_k).map(|v| &mut v.value)
+        if let Some(mrc) = &self.mrc {
+            mrc.observe(hash_k);
+        }
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.observe(hash_k);
+        }
+
+        if self.peek_hashed(hash_k).is_some() {
+            let written = self.metadata.written_at(hash_k);
+            self.stats.record(|s| {
+                s.hits += 1;
+                if let Some(written) = written {
+                    s.hit_ages.record(written.elapsed());
+                }
+            });
+            self.events.emit(CacheEvent::Accessed(hash_k));
+        } else {
+            self.stats.record(|s| s.misses += 1);
+            #[cfg(feature = "tracing")]
+            if let Some(tracer) = &self.tracer {
+                tracer.miss(hash_k);
             }
-            None => None,
+        }
+
+        self.get_mut_hashed(hash_k)
+    }
+
+    fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.peek_hashed(self.hash_k(k))
+    }
+
+    fn can_peek(&self) -> bool {
+        true
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entry_info_hashed(self.hash_k(k))
+    }
+
+    fn key_hash<Q>(&self, k: &Q) -> KeyHash
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.hash_k(k)
+    }
+
+    fn touch_hash(&mut self, hash_k: KeyHash) {
+        if self.get_mut_hashed(hash_k).is_some() {
+            self.events.emit(CacheEvent::Accessed(hash_k));
         }
     }
 
     fn remove<Q>(&mut self, k: &Q) -> Option<V>
     where
-        Q: Hash + Eq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
         let hash_k = self.hash_k(k);
+        let v = self.remove_hashed(hash_k);
 
-        self.storage.remove(&hash_k).map(|n| n.value)
+        if v.is_some() {
+            self.stats.record(|s| s.removals += 1);
+            self.events.emit(CacheEvent::Removed(hash_k));
+        }
+
+        v
     }
 
     fn clear(&mut self) {
+        let len = self.len() as u64;
+        self.stats.record(|s| s.cleared += len);
+        self.events.emit(CacheEvent::Cleared);
+        self.metadata.clear();
         self.storage.clear();
     }
 
     fn len(&self) -> usize {
         self.storage.len()
     }
+
+    fn set_record_stats(&mut self, record: bool) {
+        LruCache::set_record_stats(self, record)
+    }
+
+    fn stats(&self) -> Option<CacheStats> {
+        LruCache::stats(self)
+    }
+
+    fn reset_stats(&mut self) {
+        LruCache::reset_stats(self)
+    }
+}
+
+impl<K, V, S> EventSource for LruCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn subscribe(
+        &mut self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> CacheEvents {
+        self.events.subscribe(capacity, overflow)
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<K, V, S> Traceable for LruCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn attach_tracer(&mut self, tracer: CacheTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    fn detach_tracer(&mut self) -> Option<CacheTracer> {
+        self.tracer.take()
+    }
+}
+
+/// Shows the items from the youngest to the oldest. The cache doesn't
+/// hold on to its keys, so they are shown by their hashes.
+impl<K, V, S> fmt::Debug for LruCache<K, V, S>
+where
+    K: Eq + Hash,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LruCache")
+            .field("capacity", &self.capacity)
+            .field("entries", &DebugEntries(self))
+            .finish()
+    }
+}
+
+struct DebugEntries<'a, K, V, S>(&'a LruCache<K, V, S>)
+where
+    K: Eq + Hash,
+    S: BuildHasher;
+
+impl<K, V, S> fmt::Debug for DebugEntries<'_, K, V, S>
+where
+    K: Eq + Hash,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.0.iter_hashed().map(|(h, v)| (DebugHash(h), v)),
+            )
+            .finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, S> crate::json::JsonDump for LruCache<K, V, S>
+where
+    K: Eq + Hash,
+    V: serde::Serialize,
+    S: BuildHasher,
+{
+    fn to_json(&self) -> serde_json::Value {
+        let now = std::time::Instant::now();
+        let entries: Vec<_> = self
+            .iter_hashed()
+            .map(|(hash_k, v)| {
+                crate::json::entry_json(
+                    hash_k,
+                    v,
+                    self.metadata.info(hash_k),
+                    now,
+                )
+            })
+            .collect();
+
+        serde_json::json!({
+            "capacity": self.capacity,
+            "len": self.len(),
+            "entries": entries,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{Cache, LruCache};
-    use crate::null_hasher::BuildNullHasher;
+    use crate::{
+        null_hasher::BuildNullHasher, CacheEvent, EventSource, Overflow,
+    };
 
     #[test]
     fn test_cache() {
@@ -180,6 +538,190 @@ mod tests {
         assert_eq!(None, cache.get(&7u64));
     }
 
+    #[test]
+    fn test_stats() {
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(2);
+        assert_eq!(None, cache.stats());
+        cache.set_record_stats(true);
+
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(Some(&3), cache.get(&3));
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(Some(2), cache.remove(&2));
+        cache.clear();
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(1, stats.hits);
+        assert_eq!(1, stats.misses);
+        assert_eq!(3, stats.inserts);
+        assert_eq!(1, stats.updates);
+        assert_eq!(1, stats.capacity_evictions);
+        assert_eq!(1, stats.removals);
+        assert_eq!(1, stats.cleared);
+
+        cache.reset_stats();
+        assert_eq!(Some(Default::default()), cache.stats());
+        cache.set_record_stats(false);
+        assert_eq!(None, cache.stats());
+    }
+
+    #[test]
+    fn test_age_histograms() {
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(1);
+
+        // ages are only known for items written while recording stats
+        cache.insert(1, 1);
+        cache.set_record_stats(true);
+        cache.get(&1);
+        cache.insert(2, 2);
+        let stats = cache.stats().unwrap();
+        assert_eq!((1, 1), (stats.hits, stats.capacity_evictions));
+        assert!(stats.hit_ages.is_empty());
+        assert!(stats.eviction_ages.is_empty());
+
+        // which doesn't need metadata tracked, nor report any
+        cache.get(&2);
+        cache.insert(3, 3);
+        let stats = cache.stats().unwrap();
+        assert_eq!(1, stats.hit_ages.count());
+        assert_eq!(1, stats.eviction_ages.count());
+        assert!(stats.expiry_ages.is_empty());
+        assert_eq!(None, cache.entry_info(&3).unwrap().created_at);
+    }
+
+    #[test]
+    fn test_entry_info() {
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(2);
+        cache.insert(1, 1);
+
+        // without tracking only the weight is known
+        let info = cache.entry_info(&1).unwrap();
+        assert_eq!(None, info.created_at);
+        assert_eq!(1, info.weight);
+
+        cache.set_track_metadata(true);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        cache.get(&1);
+
+        let info = cache.entry_info(&1).unwrap();
+        assert_eq!(Some(2), info.access_count);
+        assert!(info.last_accessed_at >= info.created_at);
+        assert_eq!(None, info.time_to_live);
+        assert_eq!(Some(0), cache.entry_info(&2).unwrap().access_count);
+
+        // looking at the info didn't make 2 any younger, so it goes first
+        cache.insert(3, 3);
+        assert_eq!(None, cache.entry_info(&2));
+        assert!(cache.entry_info(&1).is_some());
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut cache: LruCache<u64, &str, BuildNullHasher> =
+            LruCache::with_capacity_and_hash_builder(
+                2,
+                BuildNullHasher,
+            );
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        cache.get(&1);
+
+        assert_eq!(
+            "LruCache { capacity: 2, entries: {\
+             0x0000000000000001: \"one\", \
+             0x0000000000000002: \"two\"} }",
+            format!("{:?}", cache)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_dump_json() {
+        use crate::JsonDump;
+
+        let mut cache: LruCache<u64, &str, BuildNullHasher> =
+            LruCache::with_capacity_and_hash_builder(
+                2,
+                BuildNullHasher,
+            );
+        cache.set_track_metadata(true);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        cache.get(&1);
+
+        let dump = cache.to_json();
+        assert_eq!(2, dump["capacity"]);
+        assert_eq!(2, dump["len"]);
+        assert_eq!("0x0000000000000001", dump["entries"][0]["hash"]);
+        assert_eq!("one", dump["entries"][0]["value"]);
+        assert_eq!(1, dump["entries"][0]["access_count"]);
+        assert_eq!(0, dump["entries"][1]["access_count"]);
+        assert!(dump["entries"][1]["ttl_ms"].is_null());
+
+        let parsed: serde_json::Value =
+            serde_json::from_str(&cache.dump_json()).unwrap();
+        assert_eq!(dump, parsed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_dump_json_unserializable() {
+        use std::collections::BTreeMap;
+
+        use crate::JsonDump;
+
+        // JSON maps can only have strings for keys
+        let mut cache: LruCache<u64, BTreeMap<(u8, u8), u8>> =
+            LruCache::with_capacity(2);
+        cache.insert(1, BTreeMap::from([((1, 2), 3)]));
+
+        let dump = cache.to_json();
+        assert_eq!(
+            "key must be a string",
+            dump["entries"][0]["value"]["error"]
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let mut cache: LruCache<u64, u64, BuildNullHasher> =
+            LruCache::with_capacity_and_hash_builder(
+                2,
+                BuildNullHasher,
+            );
+        let events = cache.subscribe(16, Overflow::DropNewest);
+
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.get(&3);
+        cache.get(&1);
+        cache.remove(&2);
+        cache.clear();
+
+        // with a null hasher each key is its own hash
+        assert_eq!(
+            vec![
+                CacheEvent::Inserted(1),
+                CacheEvent::Updated(1),
+                CacheEvent::Inserted(2),
+                CacheEvent::Evicted(1),
+                CacheEvent::Inserted(3),
+                CacheEvent::Accessed(3),
+                CacheEvent::Removed(2),
+                CacheEvent::Cleared,
+            ],
+            std::iter::from_fn(|| events.try_recv())
+                .collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn readme_snippet() {
         let mut cache: LruCache<usize, String> =
@@ -194,4 +736,38 @@ mod tests {
 
         assert_eq!(None, cache.get(&0));
     }
+
+    /// Inserting over a key used to look the old item up by the key
+    /// itself rather than by its hash, so it was never found: the insert
+    /// handed back None and the stale item stayed behind taking up room.
+    #[test]
+    fn test_insert_replaces_the_old_item() {
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(2);
+
+        assert_eq!(None, cache.insert(1, 1));
+        assert_eq!(Some(1), cache.insert(1, 2));
+        assert_eq!(1, cache.len());
+
+        // with the old item gone there is still room for another
+        assert_eq!(None, cache.insert(2, 2));
+        assert_eq!(Some(&2), cache.get(&1));
+        assert_eq!(Some(&2), cache.get(&2));
+    }
+
+    #[test]
+    fn test_reinsert_with_hashed_keys() {
+        let mut cache: LruCache<String, usize> =
+            LruCache::with_capacity(2);
+
+        assert_eq!(None, cache.insert("a".to_owned(), 0));
+        assert_eq!(Some(0), cache.insert("a".to_owned(), 1));
+        assert_eq!(1, cache.len());
+
+        cache.insert("b".to_owned(), 2);
+        cache.insert("c".to_owned(), 3);
+        assert_eq!(2, cache.len());
+        assert_eq!(None, cache.get(&"a".to_owned()));
+        assert_eq!(Some(&3), cache.get(&"c".to_owned()));
+        assert_eq!(Some(&3), cache.get("c"));
+    }
 }
@@ -0,0 +1,151 @@
+//! Optional bookkeeping about each entry in a cache, kept to one side of
+//! the entries themselves so that caches which don't track it carry none.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{cache::KeyHash, null_hasher::BuildNullHasher};
+
+/// What a cache knows about one of its entries, from
+/// [`Cache::entry_info`]. Looking it up doesn't count as an access or
+/// change the entry's place in the eviction queue.
+///
+/// The creation time, last access and access count are only known for
+/// entries written while the cache was tracking metadata.
+///
+/// [`Cache::entry_info`]: crate::Cache::entry_info
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// When the entry was last written.
+    pub created_at: Option<Instant>,
+    /// When the entry was last gotten since it was written, if it has
+    /// been.
+    pub last_accessed_at: Option<Instant>,
+    /// How many times the entry has been gotten since it was written.
+    pub access_count: Option<u64>,
+    /// How much the entry counts against the cache's capacity. None of the
+    /// caches here weigh their entries, so this is always one.
+    pub weight: u64,
+    /// How long until the entry expires, or None if it never will.
+    pub time_to_live: Option<Duration>,
+}
+
+impl EntryInfo {
+    /// What is known about an entry with no metadata at all.
+    pub(crate) fn bare() -> Self {
+        EntryInfo {
+            created_at: None,
+            last_accessed_at: None,
+            access_count: None,
+            weight: 1,
+            time_to_live: None,
+        }
+    }
+}
+
+struct Metadata {
+    created_at: Instant,
+    last_accessed_at: Option<Instant>,
+    access_count: u64,
+}
+
+/// The metadata of every entry by the hash of its key, or nothing at all
+/// when it isn't being tracked.
+///
+/// Write times are also kept while the cache records stats, for their
+/// age histograms, without [`MetadataStore::info`] reporting anything
+/// unless it was asked to track metadata too.
+#[derive(Default)]
+pub(crate) struct MetadataStore {
+    store: Option<HashMap<KeyHash, Metadata, BuildNullHasher>>,
+    tracking: bool,
+    timing: bool,
+}
+
+impl MetadataStore {
+    /// Starts or stops tracking. Stopping throws away what was tracked.
+    pub(crate) fn set_tracking(&mut self, track: bool) {
+        self.tracking = track;
+        self.keep_store();
+    }
+
+    /// Starts or stops keeping write times for
+    /// [`MetadataStore::written_at`].
+    pub(crate) fn set_timing(&mut self, time: bool) {
+        self.timing = time;
+        self.keep_store();
+    }
+
+    fn keep_store(&mut self) {
+        match (self.tracking || self.timing, &self.store) {
+            (true, None) => {
+                self.store = Some(HashMap::with_hasher(BuildNullHasher))
+            }
+            (false, Some(_)) => self.store = None,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn written(&mut self, hash_k: KeyHash) {
+        if let Some(store) = &mut self.store {
+            store.insert(
+                hash_k,
+                Metadata {
+                    created_at: Instant::now(),
+                    last_accessed_at: None,
+                    access_count: 0,
+                },
+            );
+        }
+    }
+
+    pub(crate) fn accessed(&mut self, hash_k: KeyHash) {
+        if let Some(meta) =
+            self.store.as_mut().and_then(|store| store.get_mut(&hash_k))
+        {
+            meta.last_accessed_at = Some(Instant::now());
+            meta.access_count += 1;
+        }
+    }
+
+    pub(crate) fn removed(&mut self, hash_k: KeyHash) {
+        if let Some(store) = &mut self.store {
+            store.remove(&hash_k);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        if let Some(store) = &mut self.store {
+            store.clear();
+        }
+    }
+
+    /// When an entry was last written, if that's known.
+    pub(crate) fn written_at(
+        &self,
+        hash_k: KeyHash,
+    ) -> Option<Instant> {
+        let store = self.store.as_ref()?;
+        store.get(&hash_k).map(|meta| meta.created_at)
+    }
+
+    /// Fills in what is known about an entry which is in the cache.
+    pub(crate) fn info(&self, hash_k: KeyHash) -> EntryInfo {
+        let mut info = EntryInfo::bare();
+
+        if let Some(meta) = self
+            .store
+            .as_ref()
+            .filter(|_| self.tracking)
+            .and_then(|store| store.get(&hash_k))
+        {
+            info.created_at = Some(meta.created_at);
+            info.last_accessed_at = meta.last_accessed_at;
+            info.access_count = Some(meta.access_count);
+        }
+
+        info
+    }
+}
@@ -0,0 +1,505 @@
+//! Renders what caches have been up to in the Prometheus text exposition
+//! format, or OpenMetrics, for exporters to publish.
+
+use std::{
+    fmt::{self, Write},
+    hash::{BuildHasher, Hash},
+};
+
+use crate::{Cache, CacheStats, ExpiringCache, LruCache};
+
+/// Everything there is to publish about a cache at a moment in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheMetrics {
+    /// What the cache has been up to, if it is recording stats.
+    pub stats: Option<CacheStats>,
+    /// How many items are in the cache.
+    pub size: usize,
+    /// How many items the cache holds before it starts evicting, if it
+    /// has a limit.
+    pub capacity: Option<usize>,
+    /// How much the items in the cache weigh all together. None of the
+    /// caches here weigh their items, so for now this is the same as the
+    /// size.
+    pub weight: u64,
+}
+
+impl CacheMetrics {
+    /// Adds up the metrics of the caches which together make up one, such
+    /// as the shards of a ShardedCache. There are stats if any of them
+    /// were recording, and a capacity only if every one of them has one.
+    #[cfg(feature = "shared_cache")]
+    pub(crate) fn sum<I>(parts: I) -> CacheMetrics
+    where
+        I: IntoIterator<Item = CacheMetrics>,
+    {
+        let mut sum = CacheMetrics {
+            stats: None,
+            size: 0,
+            capacity: Some(0),
+            weight: 0,
+        };
+
+        for part in parts {
+            if let Some(stats) = &part.stats {
+                sum.stats
+                    .get_or_insert_with(Default::default)
+                    .add(stats);
+            }
+            sum.size += part.size;
+            sum.weight += part.weight;
+            sum.capacity =
+                sum.capacity.zip(part.capacity).map(|(a, b)| a + b);
+        }
+
+        sum
+    }
+
+    /// Renders these metrics with a name for the cache they came from,
+    /// which becomes the `cache` label on each of them.
+    pub fn render(
+        &self,
+        name: &str,
+        format: ExpositionFormat,
+    ) -> String {
+        render_all(&[(name, *self)], format)
+    }
+}
+
+/// A cache which can report its [`CacheMetrics`].
+pub trait MeteredCache {
+    /// Gathers up the cache's metrics as they are right now.
+    fn metrics(&self) -> CacheMetrics;
+}
+
+impl<K, V, S> MeteredCache for LruCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            stats: self.stats(),
+            size: self.len(),
+            capacity: Some(self.capacity()),
+            weight: self.len() as u64,
+        }
+    }
+}
+
+impl<K, V, S> MeteredCache for ExpiringCache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            stats: self.stats(),
+            size: self.len(),
+            capacity: Some(self.capacity()),
+            weight: self.len() as u64,
+        }
+    }
+}
+
+/// The text formats metrics can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpositionFormat {
+    /// The Prometheus text exposition format, version 0.0.4.
+    Prometheus,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    /// What to send as the Content-Type of a scrape response.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::Prometheus => {
+                "text/plain; version=0.0.4; charset=utf-8"
+            }
+            ExpositionFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+}
+
+/// A collection of named caches to be rendered together.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    caches: Vec<(String, Box<dyn MeteredCache + Send + Sync>)>,
+}
+
+impl MetricsRegistry {
+    /// Makes an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a cache under a name, which becomes the `cache` label on each
+    /// of its metrics. A SharedCache can be registered by cloning it,
+    /// while other caches can be wrapped in something which locks them.
+    pub fn register<M>(&mut self, name: impl Into<String>, cache: M)
+    where
+        M: MeteredCache + Send + Sync + 'static,
+    {
+        self.caches.push((name.into(), Box::new(cache)));
+    }
+
+    /// Takes a cache out of the registry, returning whether there was one
+    /// by that name.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let len = self.caches.len();
+        self.caches.retain(|(n, _)| n != name);
+        self.caches.len() != len
+    }
+
+    /// Renders the metrics of every registered cache.
+    pub fn render(&self, format: ExpositionFormat) -> String {
+        let metrics: Vec<(&str, CacheMetrics)> = self
+            .caches
+            .iter()
+            .map(|(name, cache)| (name.as_str(), cache.metrics()))
+            .collect();
+
+        render_all(&metrics, format)
+    }
+}
+
+/// Whether a metric family counts up or goes up and down.
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// A metric family, and how to read its samples out of a cache's metrics.
+/// Each sample has an optional extra label along with its value.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    samples: fn(&CacheMetrics) -> Vec<Sample>,
+}
+
+/// A sample's extra label, if it has one, and its value.
+type Sample = (Option<(&'static str, &'static str)>, f64);
+
+const FAMILIES: &[Family] = &[
+    Family {
+        name: "cache_hits",
+        help: "Gets which found what they were looking for.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.hits),
+    },
+    Family {
+        name: "cache_misses",
+        help: "Gets which came up empty.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.misses),
+    },
+    Family {
+        name: "cache_inserts",
+        help: "Items inserted under a key which wasn't in the cache.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.inserts),
+    },
+    Family {
+        name: "cache_updates",
+        help: "Items inserted over one which was already in the cache.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.updates),
+    },
+    Family {
+        name: "cache_evictions",
+        help: "Items the cache threw out by itself, by why it did.",
+        kind: Kind::Counter,
+        samples: |m| match &m.stats {
+            Some(s) => vec![
+                (
+                    Some(("cause", "capacity")),
+                    s.capacity_evictions as f64,
+                ),
+                (Some(("cause", "expired")), s.expirations as f64),
+            ],
+            None => Vec::new(),
+        },
+    },
+    Family {
+        name: "cache_removals",
+        help: "Items taken out of the cache on purpose with a remove.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.removals),
+    },
+    Family {
+        name: "cache_cleared",
+        help: "Items thrown out by clearing the cache.",
+        kind: Kind::Counter,
+        samples: |m| stat(m, |s| s.cleared),
+    },
+    Family {
+        name: "cache_loads",
+        help:
+            "Values loaded into the cache, by whether loading worked.",
+        kind: Kind::Counter,
+        samples: |m| match &m.stats {
+            Some(s) => vec![
+                (Some(("result", "success")), s.load_successes as f64),
+                (Some(("result", "failure")), s.load_failures as f64),
+            ],
+            None => Vec::new(),
+        },
+    },
+    Family {
+        name: "cache_load_duration_seconds",
+        help: "Time spent loading values, failures included.",
+        kind: Kind::Counter,
+        samples: |m| match &m.stats {
+            Some(s) => vec![(None, s.total_load_time.as_secs_f64())],
+            None => Vec::new(),
+        },
+    },
+    Family {
+        name: "cache_size",
+        help: "Items in the cache.",
+        kind: Kind::Gauge,
+        samples: |m| vec![(None, m.size as f64)],
+    },
+    Family {
+        name: "cache_capacity",
+        help: "Items the cache holds before it starts evicting.",
+        kind: Kind::Gauge,
+        samples: |m| match m.capacity {
+            Some(capacity) => vec![(None, capacity as f64)],
+            None => Vec::new(),
+        },
+    },
+    Family {
+        name: "cache_weight",
+        help: "Total weight of the items in the cache.",
+        kind: Kind::Gauge,
+        samples: |m| vec![(None, m.weight as f64)],
+    },
+];
+
+fn stat<F>(metrics: &CacheMetrics, f: F) -> Vec<Sample>
+where
+    F: Fn(&CacheStats) -> u64,
+{
+    metrics.stats.iter().map(|s| (None, f(s) as f64)).collect()
+}
+
+fn render_all(
+    metrics: &[(&str, CacheMetrics)],
+    format: ExpositionFormat,
+) -> String {
+    let mut out = String::new();
+
+    for family in FAMILIES {
+        // writing to a String can't fail
+        let _ = render_family(&mut out, family, metrics, format);
+    }
+
+    if format == ExpositionFormat::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+
+    out
+}
+
+fn render_family(
+    out: &mut String,
+    family: &Family,
+    metrics: &[(&str, CacheMetrics)],
+    format: ExpositionFormat,
+) -> fmt::Result {
+    let samples: Vec<_> = metrics
+        .iter()
+        .flat_map(|(name, m)| {
+            (family.samples)(m).into_iter().map(move |s| (*name, s))
+        })
+        .collect();
+
+    if samples.is_empty() {
+        return Ok(());
+    }
+
+    // prometheus wants counters named with their _total suffix all the
+    // way through, where openmetrics only wants it on the samples
+    let (family_name, suffix) = match (family.kind, format) {
+        (Kind::Counter, ExpositionFormat::Prometheus) => {
+            (format!("{}_total", family.name), "")
+        }
+        (Kind::Counter, ExpositionFormat::OpenMetrics) => {
+            (family.name.to_owned(), "_total")
+        }
+        (Kind::Gauge, _) => (family.name.to_owned(), ""),
+    };
+    let kind = match family.kind {
+        Kind::Counter => "counter",
+        Kind::Gauge => "gauge",
+    };
+
+    writeln!(out, "# HELP {} {}", family_name, family.help)?;
+    writeln!(out, "# TYPE {} {}", family_name, kind)?;
+
+    for (cache, (label, value)) in samples {
+        write!(out, "{}{}{{cache=\"", family_name, suffix)?;
+        escape_label_value(out, cache);
+        out.push('"');
+        if let Some((name, value)) = label {
+            write!(out, ",{}=\"{}\"", name, value)?;
+        }
+        writeln!(out, "}} {}", value)?;
+    }
+
+    Ok(())
+}
+
+fn escape_label_value(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CacheMetrics, ExpositionFormat};
+    use crate::CacheStats;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = CacheMetrics {
+            stats: Some(CacheStats {
+                hits: 3,
+                misses: 1,
+                capacity_evictions: 2,
+                total_load_time: Duration::from_millis(1500),
+                ..Default::default()
+            }),
+            size: 10,
+            capacity: Some(100),
+            weight: 10,
+        };
+        let text =
+            metrics.render("say \"hi\"", ExpositionFormat::Prometheus);
+
+        assert!(text.contains(
+            "# TYPE cache_hits_total counter\n\
+             cache_hits_total{cache=\"say \\\"hi\\\"\"} 3\n"
+        ));
+        assert!(text.contains(
+            "cache_evictions_total{cache=\"say \\\"hi\\\"\",cause=\"capacity\"} 2\n"
+        ));
+        assert!(text.contains(
+            "cache_load_duration_seconds_total{cache=\"say \\\"hi\\\"\"} 1.5\n"
+        ));
+        assert!(text.contains(
+            "# TYPE cache_capacity gauge\n\
+             cache_capacity{cache=\"say \\\"hi\\\"\"} 100\n"
+        ));
+        assert!(text.contains(
+            "# TYPE cache_weight gauge\n\
+             cache_weight{cache=\"say \\\"hi\\\"\"} 10\n"
+        ));
+        assert!(!text.contains("# EOF"));
+    }
+
+    #[test]
+    fn test_removals_and_clears() {
+        let metrics = CacheMetrics {
+            stats: Some(CacheStats {
+                removals: 4,
+                cleared: 6,
+                ..Default::default()
+            }),
+            size: 0,
+            capacity: None,
+            weight: 0,
+        };
+        let text = metrics.render("c", ExpositionFormat::Prometheus);
+
+        assert!(text.contains("cache_removals_total{cache=\"c\"} 4\n"));
+        assert!(text.contains("cache_cleared_total{cache=\"c\"} 6\n"));
+        assert!(!text.contains("cause=\"removed\""));
+        assert!(!text.contains("cause=\"cleared\""));
+    }
+
+    #[test]
+    #[cfg(feature = "shared_cache")]
+    fn test_sharded_sums_shards() {
+        use super::MeteredCache;
+        use crate::{LruCache, ShardedCache};
+
+        let cache: ShardedCache<LruCache<usize, usize>> =
+            ShardedCache::with_shards(4, |_| {
+                let mut lru = LruCache::with_capacity(10);
+                lru.set_record_stats(true);
+                lru
+            });
+        for i in 0..8 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        cache.remove(&0);
+
+        let metrics = cache.metrics();
+        assert_eq!(7, metrics.size);
+        assert_eq!(Some(40), metrics.capacity);
+        assert_eq!(7, metrics.weight);
+        let stats = metrics.stats.unwrap();
+        assert_eq!(8, stats.hits);
+        assert_eq!(1, stats.removals);
+    }
+
+    #[test]
+    #[cfg(feature = "shared_cache")]
+    fn test_registry_openmetrics() {
+        use super::MetricsRegistry;
+        use crate::{Cache, LruCache, SharedCache};
+
+        let mut lru: LruCache<usize, usize> =
+            LruCache::with_capacity(10);
+        lru.set_record_stats(true);
+        lru.insert(1, 1);
+        lru.get(&1);
+        let shared = SharedCache::with_cache(lru);
+
+        let mut registry = MetricsRegistry::new();
+        registry.register("users", shared.clone());
+        registry.register(
+            "sessions",
+            SharedCache::with_cache(
+                LruCache::<usize, usize>::with_capacity(5),
+            ),
+        );
+        let text = registry.render(ExpositionFormat::OpenMetrics);
+
+        // families are grouped, so both caches come under one TYPE line
+        assert!(text.contains(
+            "# TYPE cache_size gauge\n\
+             cache_size{cache=\"users\"} 1\n\
+             cache_size{cache=\"sessions\"} 0\n"
+        ));
+        assert!(text.contains(
+            "# TYPE cache_hits counter\n\
+             cache_hits_total{cache=\"users\"} 1\n\
+             # HELP cache_misses"
+        ));
+        assert!(text.ends_with("# EOF\n"));
+
+        assert!(registry.unregister("users"));
+        assert!(!registry.unregister("users"));
+        assert!(!registry
+            .render(ExpositionFormat::Prometheus)
+            .contains("users"));
+    }
+}
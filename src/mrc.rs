@@ -0,0 +1,391 @@
+//! Estimates how the hit ratio of an LRU cache would change with its
+//! capacity, by watching which keys are looked up without storing any of
+//! their values.
+//!
+//! This follows SHARDS (Waldspurger et al, FAST '15): only keys whose hash
+//! falls under a threshold are tracked, which samples a fixed fraction of
+//! the keys along with every reference to them. The reuse distance of each
+//! sampled reference, which is the number of distinct keys looked up since
+//! the last time it was, is found with a Fenwick tree over the times of
+//! each key's latest reference, and scaled back up by the sampling rate.
+//!
+//! So that a cache with ever more keys doesn't grow the estimator along
+//! with it, the number of sampled keys is bounded as in the paper's
+//! fixed-size variant: once there are too many the threshold is lowered,
+//! and the keys whose hashes no longer fall under it are let go.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::{cache::KeyHash, null_hasher::BuildNullHasher};
+
+/// Sampling thresholds are out of this many.
+const MODULUS: u64 = 1 << 24;
+
+/// The fewest reference times the Fenwick tree makes room for.
+const MIN_TIMES: usize = 1024;
+
+/// How many keys are sampled at most, unless told otherwise.
+const DEFAULT_MAX_KEYS: usize = 8192;
+
+/// Counts over a range of reference times, in logarithmic time for both
+/// updates and queries.
+struct Fenwick(Vec<i64>);
+
+impl Fenwick {
+    fn with_len(len: usize) -> Self {
+        Fenwick(vec![0; len + 1])
+    }
+
+    fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    fn add(&mut self, i: usize, n: i64) {
+        let mut i = i + 1;
+        while i < self.0.len() {
+            self.0[i] += n;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// The sum over every time up to and including `i`.
+    fn sum(&self, i: usize) -> i64 {
+        let mut i = i + 1;
+        let mut sum = 0;
+        while i > 0 {
+            sum += self.0[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+}
+
+/// Every sampled key and the time it was last referenced. Values are never
+/// kept, which is what makes the ghost so much smaller than the cache.
+struct Ghost {
+    last: HashMap<KeyHash, usize, BuildNullHasher>,
+    /// The sampled keys by their mixed hashes, so the largest can be let
+    /// go when there are too many.
+    sampled: BTreeSet<(u64, KeyHash)>,
+    max_keys: usize,
+    threshold: u64,
+    times: Fenwick,
+    clock: usize,
+    /// How many references each scaled reuse distance stands for, with
+    /// each sampled reference standing for one over the rate it was
+    /// sampled at.
+    distances: BTreeMap<u64, f64>,
+    weight: f64,
+    references: u64,
+}
+
+impl Ghost {
+    fn observe(&mut self, hash_k: KeyHash, mixed: u64) {
+        // the threshold may have come down since the caller checked
+        if mixed >= self.threshold {
+            return;
+        }
+        if self.clock == self.times.len() {
+            self.compact();
+        }
+
+        let rate = self.threshold as f64 / MODULUS as f64;
+        match self.last.insert(hash_k, self.clock) {
+            Some(t) => {
+                let since =
+                    self.times.sum(self.clock - 1) - self.times.sum(t);
+                let distance = (since as f64 / rate) as u64;
+                *self.distances.entry(distance).or_default() +=
+                    1.0 / rate;
+                self.times.add(t, -1);
+            }
+            None => {
+                self.sampled.insert((mixed, hash_k));
+            }
+        }
+
+        self.times.add(self.clock, 1);
+        self.clock += 1;
+        self.weight += 1.0 / rate;
+        self.references += 1;
+
+        if self.last.len() > self.max_keys {
+            self.lower_threshold();
+        }
+    }
+
+    /// Lowers the threshold to the largest sampled hash and lets go of
+    /// every key which no longer falls under it.
+    fn lower_threshold(&mut self) {
+        if let Some(&(mixed, _)) = self.sampled.iter().next_back() {
+            self.threshold = mixed.max(1);
+        }
+
+        while let Some(&(mixed, hash_k)) =
+            self.sampled.iter().next_back()
+        {
+            if mixed < self.threshold {
+                break;
+            }
+            self.sampled.remove(&(mixed, hash_k));
+            if let Some(t) = self.last.remove(&hash_k) {
+                self.times.add(t, -1);
+            }
+        }
+    }
+
+    /// Renumbers the latest reference of each key from zero, keeping their
+    /// order, so the tree only needs to be about as big as the number of
+    /// keys rather than the number of references.
+    fn compact(&mut self) {
+        let mut live: Vec<(usize, KeyHash)> =
+            self.last.iter().map(|(h, t)| (*t, *h)).collect();
+        live.sort_unstable();
+
+        self.times = Fenwick::with_len((live.len() * 2).max(MIN_TIMES));
+        for (t, (_, hash_k)) in live.iter().enumerate() {
+            self.last.insert(*hash_k, t);
+            self.times.add(t, 1);
+        }
+        self.clock = live.len();
+    }
+}
+
+/// Watches the keys looked up in a cache and estimates its hit ratio at
+/// other capacities. Clones share their observations, so one can be
+/// attached to a cache with [`LruCache::attach_mrc_estimator`] while
+/// another is kept to read the curve from.
+///
+/// Keys which aren't sampled cost a hash and a comparison; sampled keys
+/// take a lock and a couple of logarithmic tree updates. However many keys
+/// it sees, it never tracks more than its maximum, lowering the sample
+/// rate instead.
+///
+/// [`LruCache::attach_mrc_estimator`]: crate::LruCache::attach_mrc_estimator
+#[derive(Clone)]
+pub struct MrcEstimator {
+    /// A copy of the ghost's threshold, to check keys against without
+    /// taking the lock.
+    threshold: Arc<AtomicU64>,
+    ghost: Arc<Mutex<Ghost>>,
+}
+
+impl MrcEstimator {
+    /// Makes an estimator which tracks about `rate` of the keys it sees,
+    /// between 0 and 1, and no more than 8192 of them. Something like 0.01
+    /// is plenty for caches with hundreds of thousands of keys, while
+    /// small caches want more.
+    pub fn with_sample_rate(rate: f64) -> Self {
+        Self::with_sample_rate_and_max_keys(rate, DEFAULT_MAX_KEYS)
+    }
+
+    /// Makes an estimator which tracks about `rate` of the keys it sees,
+    /// lowering the rate as it needs to so as to track no more than
+    /// `max_keys` of them.
+    pub fn with_sample_rate_and_max_keys(
+        rate: f64,
+        max_keys: usize,
+    ) -> Self {
+        let threshold =
+            ((rate.clamp(0.0, 1.0) * MODULUS as f64) as u64).max(1);
+
+        MrcEstimator {
+            threshold: Arc::new(AtomicU64::new(threshold)),
+            ghost: Arc::new(Mutex::new(Ghost {
+                last: HashMap::with_hasher(BuildNullHasher),
+                sampled: BTreeSet::new(),
+                max_keys: max_keys.max(1),
+                threshold,
+                times: Fenwick::with_len(MIN_TIMES),
+                clock: 0,
+                distances: BTreeMap::new(),
+                weight: 0.0,
+                references: 0,
+            })),
+        }
+    }
+
+    /// The fraction of keys which are sampled at the moment.
+    pub fn sample_rate(&self) -> f64 {
+        self.threshold.load(Ordering::Relaxed) as f64 / MODULUS as f64
+    }
+
+    /// Notes a reference to the key with the given hash, as given by
+    /// [`Cache::key_hash`].
+    ///
+    /// [`Cache::key_hash`]: crate::Cache::key_hash
+    pub fn observe(&self, hash_k: KeyHash) {
+        // the cache's own hash may not be well mixed, with a null hasher
+        // say, so mix it before picking by it
+        let mixed = mix(hash_k) % MODULUS;
+        if mixed < self.threshold.load(Ordering::Relaxed) {
+            let mut ghost = self.lock();
+            ghost.observe(hash_k, mixed);
+            self.threshold.store(ghost.threshold, Ordering::Relaxed);
+        }
+    }
+
+    /// The hit ratio an LRU cache would have had at each capacity, had it
+    /// seen the references observed so far.
+    pub fn curve(&self) -> MissRatioCurve {
+        let ghost = self.lock();
+        let mut hits = 0.0;
+
+        let points = ghost
+            .distances
+            .iter()
+            .map(|(distance, n)| {
+                hits += n;
+                // a reference at distance d hits in any cache holding
+                // more than d other keys
+                (*distance as usize + 1, hits / ghost.weight)
+            })
+            .collect();
+
+        MissRatioCurve {
+            points,
+            references: ghost.references,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ghost> {
+        self.ghost.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The finalizer from splitmix64.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// An estimate of an LRU cache's hit ratio by its capacity, from an
+/// [`MrcEstimator`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissRatioCurve {
+    points: Vec<(usize, f64)>,
+    references: u64,
+}
+
+impl MissRatioCurve {
+    /// The estimated hit ratio of a cache holding `capacity` items.
+    pub fn hit_ratio(&self, capacity: usize) -> f64 {
+        match self.points.partition_point(|(c, _)| *c <= capacity) {
+            0 => 0.0,
+            i => self.points[i - 1].1,
+        }
+    }
+
+    /// The estimated miss ratio of a cache holding `capacity` items.
+    pub fn miss_ratio(&self, capacity: usize) -> f64 {
+        1.0 - self.hit_ratio(capacity)
+    }
+
+    /// Each capacity at which the hit ratio goes up, with the hit ratio
+    /// from there on, smallest first.
+    pub fn points(&self) -> &[(usize, f64)] {
+        &self.points
+    }
+
+    /// How many sampled references the curve was drawn from. Curves drawn
+    /// from only a few are not to be trusted.
+    pub fn references(&self) -> u64 {
+        self.references
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MrcEstimator;
+    use crate::{Cache, LruCache};
+
+    #[test]
+    fn test_exact_curve() {
+        let mrc = MrcEstimator::with_sample_rate(1.0);
+
+        // looping over ten keys misses every time in an LRU cache which
+        // can't hold all ten, and hits every time after the first loop in
+        // one which can
+        for _ in 0..10 {
+            for k in 0..10 {
+                mrc.observe(k);
+            }
+        }
+
+        let curve = mrc.curve();
+        assert_eq!(100, curve.references());
+        assert_eq!(0.0, curve.hit_ratio(9));
+        assert_eq!(0.9, curve.hit_ratio(10));
+        assert_eq!(0.9, curve.hit_ratio(1000));
+        assert_eq!(&[(10, 0.9)], curve.points());
+    }
+
+    #[test]
+    fn test_sampled_curve() {
+        let mrc = MrcEstimator::with_sample_rate(0.1);
+
+        // enough references to need compacting several times over
+        for _ in 0..20 {
+            for k in 0..2000u64 {
+                mrc.observe(k.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            }
+        }
+
+        let curve = mrc.curve();
+        assert!(curve.references() > 0);
+        assert!(curve.hit_ratio(1500) < 0.05);
+        assert!(curve.hit_ratio(2500) > 0.9);
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let mrc = MrcEstimator::with_sample_rate_and_max_keys(1.0, 100);
+
+        // far more keys than it may track, so it samples fewer of them
+        for _ in 0..20 {
+            for k in 0..2000u64 {
+                mrc.observe(k.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            }
+        }
+
+        let ghost = mrc.lock();
+        assert!(ghost.last.len() <= 100);
+        assert_eq!(ghost.last.len(), ghost.sampled.len());
+        assert!(ghost.times.len() <= super::MIN_TIMES);
+        drop(ghost);
+        assert!(mrc.sample_rate() < 0.1);
+
+        let curve = mrc.curve();
+        assert!(curve.hit_ratio(1500) < 0.05);
+        assert!(curve.hit_ratio(2500) > 0.9);
+    }
+
+    #[test]
+    fn test_attached_to_cache() {
+        let mrc = MrcEstimator::with_sample_rate(1.0);
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(2);
+        cache.attach_mrc_estimator(mrc.clone());
+
+        for k in [1, 2, 3, 1, 2, 3] {
+            if cache.get(&k).is_none() {
+                cache.insert(k, k);
+            }
+        }
+
+        // the cache itself is too small to hit, but one more would do
+        let curve = mrc.curve();
+        assert_eq!(0.0, curve.hit_ratio(2));
+        assert_eq!(0.5, curve.hit_ratio(3));
+
+        assert!(cache.detach_mrc_estimator().is_some());
+        cache.get(&1);
+        assert_eq!(6, mrc.curve().references());
+    }
+}
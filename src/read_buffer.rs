@@ -0,0 +1,114 @@
+//! Records which keys were read from a SharedCache under a shared lock, so
+//! that the reads can be replayed into the eviction queue later on by
+//! whoever next takes the lock exclusively. HotKeys records lookups and
+//! writes the same way, so that it only takes its lock once in a while.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::cache::KeyHash;
+
+/// How many stripes the buffer is split into. Each thread sticks to one
+/// stripe, so this is about how many readers can record at once without
+/// tripping over each other.
+const STRIPES: usize = 16;
+
+/// How many reads a stripe holds before it is considered full.
+const STRIPE_CAPACITY: usize = 64;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: Cell<usize> =
+        Cell::new(NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES);
+}
+
+/// A lossy record of reads. Recording never waits: if the stripe for the
+/// current thread is busy or full, the read is simply forgotten, which
+/// costs a little accuracy in the eviction order and nothing else.
+pub(crate) struct ReadBuffer<T = KeyHash> {
+    stripes: Box<[Mutex<Vec<T>>]>,
+}
+
+impl<T> ReadBuffer<T> {
+    pub(crate) fn new() -> Self {
+        ReadBuffer {
+            stripes: (0..STRIPES)
+                .map(|_| {
+                    Mutex::new(Vec::with_capacity(STRIPE_CAPACITY))
+                })
+                .collect(),
+        }
+    }
+
+    /// Records a read, returning true if the stripe it went into is now
+    /// full and ought to be drained.
+    pub(crate) fn record(&self, read: T) -> bool {
+        self.record_with(|| read)
+    }
+
+    /// Like [`ReadBuffer::record`], but only makes what it records if
+    /// there is room for it.
+    pub(crate) fn record_with<F>(&self, f: F) -> bool
+    where
+        F: FnOnce() -> T,
+    {
+        let stripe = &self.stripes[STRIPE.with(|s| s.get())];
+
+        match stripe.try_lock() {
+            Ok(mut reads) if reads.len() < STRIPE_CAPACITY => {
+                reads.push(f());
+                reads.len() == STRIPE_CAPACITY
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Hands every recorded read to `f`, oldest first within each stripe,
+    /// and empties the buffer.
+    pub(crate) fn drain<F>(&self, mut f: F)
+    where
+        F: FnMut(T),
+    {
+        for stripe in self.stripes.iter() {
+            let mut reads =
+                stripe.lock().unwrap_or_else(|e| e.into_inner());
+            for read in reads.drain(..) {
+                f(read);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadBuffer, STRIPE_CAPACITY};
+
+    #[test]
+    fn test_read_buffer() {
+        let buffer = ReadBuffer::new();
+
+        for hash_k in 0..(STRIPE_CAPACITY as u64 - 1) {
+            assert!(!buffer.record(hash_k));
+        }
+        assert!(buffer.record(99));
+
+        // a full stripe drops reads rather than waiting
+        assert!(buffer.record(100));
+
+        let mut drained = Vec::new();
+        buffer.drain(|hash_k| drained.push(hash_k));
+        assert_eq!(STRIPE_CAPACITY, drained.len());
+        assert_eq!(Some(&99), drained.last());
+
+        drained.clear();
+        buffer.drain(|hash_k| drained.push(hash_k));
+        assert!(drained.is_empty());
+    }
+}
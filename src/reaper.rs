@@ -0,0 +1,109 @@
+//! A background thread which keeps a shared ExpiringCache from holding on
+//! to dead entries forever.
+
+use parking_lot::RwLock;
+use std::{
+    hash::{BuildHasher, Hash},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::expiring_cache::{ExpiringCache, Now};
+
+/// How many expired entries the reaper removes each time it takes the
+/// write lock, so that it never keeps other threads waiting for long.
+const REAP_BATCH_SIZE: usize = 64;
+
+/// Handle to a reaper thread started by [`SharedCache::spawn_reaper`].
+/// The thread stops on its own once every handle to the cache has been
+/// dropped; dropping this handle leaves it running, and [`shutdown`]
+/// stops it right away.
+///
+/// [`SharedCache::spawn_reaper`]: crate::SharedCache::spawn_reaper
+/// [`shutdown`]: Reaper::shutdown
+pub struct Reaper {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Reaper {
+    pub(crate) fn spawn<K, V, S>(
+        cache: Weak<RwLock<ExpiringCache<K, V, S>>>,
+        interval: Duration,
+    ) -> Self
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("mysterious_cache reaper".to_owned())
+            .spawn(move || reap(cache, interval, thread_stop))
+            .expect("failed to spawn reaper thread");
+
+        Reaper { stop, thread }
+    }
+
+    /// Whether the reaper thread is still going.
+    pub fn is_running(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    /// Stops the reaper thread and waits for it to finish.
+    pub fn shutdown(self) {
+        self.stop.store(true, Ordering::Release);
+        self.thread.thread().unpark();
+        // the reaper never panics on its own, and if purging panicked
+        // the cache is no worse off than before
+        let _ = self.thread.join();
+    }
+}
+
+fn reap<K, V, S>(
+    cache: Weak<RwLock<ExpiringCache<K, V, S>>>,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+) where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    loop {
+        // park can wake up early, so keep waiting until the interval has
+        // really passed or we've been told to stop
+        let wake_at = Instant::now() + interval;
+        loop {
+            if stop.load(Ordering::Acquire) {
+                return;
+            }
+
+            let now = Instant::now();
+            if now >= wake_at {
+                break;
+            }
+
+            thread::park_timeout(wake_at - now);
+        }
+
+        let cache = match cache.upgrade() {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        let now = Now::new();
+        let expired = cache.read().expired_hashes(now);
+
+        for batch in expired.chunks(REAP_BATCH_SIZE) {
+            if stop.load(Ordering::Acquire) {
+                return;
+            }
+
+            cache.write().purge_hashed(batch, now);
+        }
+    }
+}
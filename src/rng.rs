@@ -0,0 +1,46 @@
+//! A tiny xorshift generator for the few places a cache wants to flip a
+//! coin. It is nowhere near good enough for anything that matters, which
+//! is fine, because nothing here does.
+
+use std::{
+    collections::hash_map::RandomState, hash::BuildHasher,
+    time::Instant,
+};
+
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    /// Seeds a new generator from the standard library's random hash
+    /// keys, which is the only randomness we have without a dependency.
+    pub(crate) fn new() -> Self {
+        // xorshift gets stuck at zero, so make sure we never start there
+        Rng(RandomState::new().hash_one(Instant::now()) | 1)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `(0, 1]`, which is safe to take the logarithm of.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+
+    #[test]
+    fn test_range() {
+        let mut rng = Rng::new();
+
+        for _ in 0..10_000 {
+            let f = rng.next_f64();
+            assert!(f > 0.0 && f <= 1.0);
+        }
+    }
+}
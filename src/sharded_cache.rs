@@ -0,0 +1,318 @@
+use parking_lot::RwLock;
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{CacheMetrics, MeteredCache};
+use crate::{
+    cache::{Cache, ConcurrentCache},
+    metadata::EntryInfo,
+};
+
+/// The shards themselves, along with the hasher which picks between them.
+struct Shards<C, S> {
+    shards: Box<[RwLock<C>]>,
+    hash_builder: S,
+}
+
+/// A cache split into a number of shards which are each locked on their
+/// own, so that threads working with keys in different shards don't wait
+/// on each other like they would with a single [`SharedCache`].
+///
+/// Each shard is a whole cache in its own right, evicting only amongst its
+/// own keys, so a sharded LruCache is only approximately least recently
+/// used across the whole. Keys are spread evenly, so giving each shard an
+/// even share of the total capacity works out.
+///
+/// [`SharedCache`]: crate::SharedCache
+pub struct ShardedCache<C, S = RandomState>(Arc<Shards<C, S>>)
+where
+    C: Cache,
+    C::Value: Clone,
+    S: BuildHasher;
+
+impl<C> ShardedCache<C, RandomState>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    /// Makes a cache with a number of shards, each of which is made by
+    /// calling `make_shard` with its index.
+    pub fn with_shards<F>(shards: usize, make_shard: F) -> Self
+    where
+        F: FnMut(usize) -> C,
+    {
+        Self::with_shards_and_hash_builder(
+            shards,
+            make_shard,
+            Default::default(),
+        )
+    }
+}
+
+impl<C, S> ShardedCache<C, S>
+where
+    C: Cache,
+    C::Value: Clone,
+    S: BuildHasher,
+{
+    /// Makes a cache with a number of shards and the hasher used to pick
+    /// which shard a key goes in. Panics if asked for zero shards.
+    pub fn with_shards_and_hash_builder<F>(
+        shards: usize,
+        make_shard: F,
+        hash_builder: S,
+    ) -> Self
+    where
+        F: FnMut(usize) -> C,
+    {
+        assert!(shards > 0, "a ShardedCache needs at least one shard");
+
+        let shards =
+            (0..shards).map(make_shard).map(RwLock::new).collect();
+
+        Self(Arc::new(Shards {
+            shards,
+            hash_builder,
+        }))
+    }
+
+    /// Inserts an item into the cache.
+    pub fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        self.shard(&k).write().insert(k, v)
+    }
+
+    /// Get an item from the cache. This clones it to minimize the lock time of
+    /// the shard.
+    pub fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(k).write().get(k).cloned()
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item.
+    pub fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(k).read().entry_info(k)
+    }
+
+    /// Remove an item from the cache, returning the removed item if it existed.
+    pub fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(k).write().remove(k)
+    }
+
+    /// Clears every shard. Shards are cleared one after another rather than
+    /// all at once, so items inserted while this is going on may survive.
+    pub fn clear(&self) {
+        for shard in self.0.shards.iter() {
+            shard.write().clear();
+        }
+    }
+
+    /// The number of elements across all of the shards at present.
+    pub fn len(&self) -> usize {
+        self.0.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// Whether every shard is empty at present.
+    pub fn is_empty(&self) -> bool {
+        self.0.shards.iter().all(|shard| shard.read().is_empty())
+    }
+
+    /// The number of shards the cache is split into.
+    pub fn shard_count(&self) -> usize {
+        self.0.shards.len()
+    }
+
+    fn shard<Q>(&self, k: &Q) -> &RwLock<C>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash_k = self.0.hash_builder.hash_one(k);
+
+        &self.0.shards[(hash_k % self.0.shards.len() as u64) as usize]
+    }
+}
+
+/// Adds up the metrics of every shard, reading each under its own lock
+/// one after another.
+#[cfg(feature = "metrics")]
+impl<C, S> MeteredCache for ShardedCache<C, S>
+where
+    C: Cache + MeteredCache,
+    C::Value: Clone,
+    S: BuildHasher,
+{
+    fn metrics(&self) -> CacheMetrics {
+        CacheMetrics::sum(
+            self.0.shards.iter().map(|shard| shard.read().metrics()),
+        )
+    }
+}
+
+impl<C, S> ConcurrentCache for ShardedCache<C, S>
+where
+    C: Cache,
+    C::Value: Clone,
+    S: BuildHasher,
+{
+    type Key = C::Key;
+    type Value = C::Value;
+
+    fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        ShardedCache::insert(self, k, v)
+    }
+
+    fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ShardedCache::get(self, k)
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ShardedCache::entry_info(self, k)
+    }
+
+    fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ShardedCache::remove(self, k)
+    }
+
+    fn clear(&self) {
+        ShardedCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        ShardedCache::len(self)
+    }
+}
+
+impl<C, S> Clone for ShardedCache<C, S>
+where
+    C: Cache,
+    C::Value: Clone,
+    S: BuildHasher,
+{
+    fn clone(&self) -> Self {
+        ShardedCache(self.0.clone())
+    }
+}
+
+/// Shows each shard in turn, reading it if nobody is writing to it.
+impl<C, S> fmt::Debug for ShardedCache<C, S>
+where
+    C: Cache + fmt::Debug,
+    C::Value: Clone,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedCache")
+            .field("shards", &DebugShards(&self.0.shards))
+            .finish()
+    }
+}
+
+struct DebugShards<'a, C>(&'a [RwLock<C>]);
+
+impl<C> fmt::Debug for DebugShards<'_, C>
+where
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_list();
+        for shard in self.0 {
+            match shard.try_read() {
+                Some(shard) => d.entry(&*shard),
+                None => d.entry(&format_args!("<locked>")),
+            };
+        }
+        d.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{Cache, LruCache, ShardedCache};
+
+    #[test]
+    fn test_debug() {
+        let cache: ShardedCache<LruCache<usize, &str>> =
+            ShardedCache::with_shards(2, |_| {
+                LruCache::with_capacity(2)
+            });
+        cache.insert(1, "one");
+
+        let debug = format!("{:?}", cache);
+        assert!(debug.starts_with("ShardedCache { shards: [LruCache {"));
+        assert!(debug.contains("\"one\""));
+
+        let _shard = cache.0.shards[0].write();
+        assert!(format!("{:?}", cache).contains("<locked>"));
+    }
+
+    #[test]
+    fn test_sharded_cache() {
+        let cache: ShardedCache<LruCache<usize, usize>> =
+            ShardedCache::with_shards(8, |_| {
+                LruCache::with_capacity(100)
+            });
+        assert_eq!(8, cache.shard_count());
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for k in (t * 100)..((t + 1) * 100) {
+                        cache.insert(k, k * 2);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(400, cache.len());
+        assert_eq!(Some(84), cache.get(&42));
+        assert_eq!(Some(84), cache.remove(&42));
+        assert_eq!(None, cache.get(&42));
+        assert_eq!(399, cache.len());
+
+        // keys should be spread out, rather than all landing in one shard
+        assert!(cache
+            .0
+            .shards
+            .iter()
+            .all(|shard| shard.read().len() > 0));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -1,79 +1,938 @@
-use parking_lot::RwLock;
-use std::{hash::Hash, marker::PhantomData, sync::Arc};
+use arc_swap::ArcSwapOption;
+use parking_lot::{
+    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock,
+    RwLockReadGuard, RwLockWriteGuard,
+};
+use std::{
+    borrow::Borrow,
+    error::Error,
+    fmt,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::cache::Cache;
+#[cfg(feature = "metrics")]
+use crate::metrics::{CacheMetrics, MeteredCache};
+#[cfg(feature = "tracing")]
+use crate::trace::{CacheTracer, Traceable};
+use crate::{
+    cache::{Cache, ConcurrentCache, KeyHash},
+    events::{CacheEvents, EventSource, Overflow},
+    hot_keys::HotKeys,
+    metadata::EntryInfo,
+    read_buffer::ReadBuffer,
+    reaper::Reaper,
+    single_flight::{Boarding, Flights},
+    stats::{AtomicStats, CacheStats, Counter},
+    ExpiringCache,
+};
+
+/// What the `try_` methods on [`SharedCache`] give back when they would
+/// have had to wait for the lock, along with anything they were given
+/// which didn't make it into the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock<T = ()>(pub T);
+
+/// What a `try_` insert hands back when it would have had to wait: the
+/// key and value which didn't make it in.
+type Rejected<C> = WouldBlock<(<C as Cache>::Key, <C as Cache>::Value)>;
+
+impl<T> fmt::Display for WouldBlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the cache is locked by somebody else")
+    }
+}
+
+impl<T: fmt::Debug> Error for WouldBlock<T> {}
 
 /// Wrapper for an LruCache which is shareable across thread boundaries.
-pub struct SharedCache<C, K, V>(
-    Arc<RwLock<C>>,
-    PhantomData<K>,
-    PhantomData<V>,
-)
+pub struct SharedCache<C>
 where
-    C: Cache<K, V>,
-    K: Eq + Hash,
-    V: Clone;
+    C: Cache,
+    C::Value: Clone,
+{
+    cache: Arc<RwLock<C>>,
+    read_buffer: Option<Arc<ReadBuffer>>,
+    flights: Arc<Flights>,
+    stats: Arc<AtomicStats>,
+    hot_keys: Arc<ArcSwapOption<HotKeys<C::Key>>>,
+    #[cfg(feature = "tracing")]
+    tracer: Arc<ArcSwapOption<CacheTracer>>,
+}
 
-impl<C, K, V> SharedCache<C, K, V>
+impl<C> SharedCache<C>
 where
-    C: Cache<K, V>,
-    K: Eq + Hash,
-    V: Clone,
+    C: Cache,
+    C::Value: Clone,
 {
     /// Wraps a cache into a shared cache accessor, making it safe to move
     /// across thread boundaries. Enforces an additional constraint of Clone on
     /// values.
     pub fn with_cache(cache: C) -> Self {
-        Self(Arc::from(RwLock::from(cache)), PhantomData, PhantomData)
+        Self {
+            cache: Arc::from(RwLock::from(cache)),
+            read_buffer: None,
+            flights: Arc::new(Flights::new()),
+            stats: Arc::new(AtomicStats::default()),
+            hot_keys: Arc::new(ArcSwapOption::empty()),
+            #[cfg(feature = "tracing")]
+            tracer: Arc::new(ArcSwapOption::empty()),
+        }
+    }
+
+    /// Wraps a cache like [`SharedCache::with_cache`], except that gets only
+    /// take a shared lock so that they can all happen at once. Since that
+    /// means gets can't move items up the eviction queue, they are written
+    /// down and replayed the next time something takes the lock to write,
+    /// or when enough of them pile up. Some are lost when many threads are
+    /// reading at once, so the eviction order is only roughly what it would
+    /// otherwise be, and items in an ExpiringCache only expire, refresh, or
+    /// see their [`Expiry`] policy when the reads are replayed.
+    ///
+    /// Caches which can't [`Cache::peek`] are read under the lock to write
+    /// all the same, just as with [`SharedCache::with_cache`].
+    ///
+    /// [`Expiry`]: crate::Expiry
+    pub fn with_buffered_reads(cache: C) -> Self {
+        let read_buffer = match cache.can_peek() {
+            true => Some(Arc::new(ReadBuffer::new())),
+            false => None,
+        };
+
+        Self {
+            cache: Arc::from(RwLock::from(cache)),
+            read_buffer,
+            flights: Arc::new(Flights::new()),
+            stats: Arc::new(AtomicStats::default()),
+            hot_keys: Arc::new(ArcSwapOption::empty()),
+            #[cfg(feature = "tracing")]
+            tracer: Arc::new(ArcSwapOption::empty()),
+        }
     }
 
     /// Inserts an item into the cache.
-    pub fn insert(&self, k: K, v: V) -> Option<V> {
-        self.0.write().insert(k, v)
+    pub fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        let mut cache = self.write();
+        let old_v = self.insert_into(&mut cache, k, v);
+        drop(cache);
+        old_v
+    }
+
+    /// Like [`SharedCache::insert`], but gives up straight away rather than
+    /// wait if somebody else has the lock, handing back the item.
+    pub fn try_insert(
+        &self,
+        k: C::Key,
+        v: C::Value,
+    ) -> Result<Option<C::Value>, Rejected<C>> {
+        self.insert_within(k, v, None)
+    }
+
+    /// Like [`SharedCache::insert`], but gives up rather than wait any
+    /// longer than `timeout` if somebody else has the lock, handing back
+    /// the item.
+    pub fn try_insert_timeout(
+        &self,
+        k: C::Key,
+        v: C::Value,
+        timeout: Duration,
+    ) -> Result<Option<C::Value>, Rejected<C>> {
+        self.insert_within(k, v, Some(timeout))
     }
 
     /// Get an item from the cache. This clones it to minimize the lock time of
     /// the cache.
-    pub fn get(&self, k: &K) -> Option<V> {
-        self.0.write().get(k).map(|v| v.clone())
+    pub fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = self.lookup(k, true);
+        self.record_lookup(v.is_some());
+        v
+    }
+
+    /// Like [`SharedCache::get`], but gives up straight away rather than
+    /// wait if somebody else has the lock.
+    pub fn try_get<Q>(
+        &self,
+        k: &Q,
+    ) -> Result<Option<C::Value>, WouldBlock>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_within(k, None)
+    }
+
+    /// Like [`SharedCache::get`], but gives up rather than wait any longer
+    /// than `timeout` if somebody else has the lock.
+    pub fn try_get_timeout<Q>(
+        &self,
+        k: &Q,
+        timeout: Duration,
+    ) -> Result<Option<C::Value>, WouldBlock>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_within(k, Some(timeout))
+    }
+
+    /// Get a reference straight into the cache, rather than a clone, moving
+    /// the item up the eviction queue just as [`SharedCache::get`] would.
+    /// The item can be changed in place through the guard too.
+    ///
+    /// The guard holds the lock to write for as long as it's around, so
+    /// every other thread using the cache waits until it is dropped. Keep
+    /// hold of it only for as long as it takes to look at the item, and
+    /// don't use the cache again on the same thread until it's gone, as
+    /// that deadlocks.
+    pub fn get_ref<Q>(
+        &self,
+        k: &Q,
+    ) -> Option<MappedRwLockWriteGuard<'_, C::Value>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = RwLockWriteGuard::try_map(self.write(), |cache| {
+            self.observe(|| cache.key_hash(k));
+            cache.get_mut(k)
+        })
+        .ok();
+        self.record_lookup(v.is_some());
+        v
+    }
+
+    /// Get a reference straight into the cache without moving the item up
+    /// the eviction queue. The guard holds the lock to read, so other
+    /// readers carry on but writers, and gets on a cache made with
+    /// [`SharedCache::with_cache`], wait until it is dropped. The same
+    /// advice about keeping it briefly applies as for
+    /// [`SharedCache::get_ref`].
+    pub fn peek_ref<Q>(
+        &self,
+        k: &Q,
+    ) -> Option<MappedRwLockReadGuard<'_, C::Value>>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = RwLockReadGuard::try_map(self.read(), |cache| {
+            self.observe(|| cache.key_hash(k));
+            cache.peek(k)
+        })
+        .ok();
+        self.record_lookup(v.is_some());
+        v
+    }
+
+    /// Get an item from the cache, or if it isn't there, make it with `f`
+    /// and insert it. When several threads miss the same key at once only
+    /// one of them runs its `f`, and the rest wait for it and then use the
+    /// value it made. The lock is not held while `f` runs.
+    ///
+    /// Should `f` panic, the panic carries on up the thread that ran it
+    /// and one of the waiting threads runs its own `f` instead, so nobody
+    /// is left waiting forever and the cache carries on as usual.
+    pub fn get_or_insert_with<F>(&self, k: C::Key, f: F) -> C::Value
+    where
+        F: FnOnce() -> C::Value,
+    {
+        match self.try_get_or_insert_with(k, || {
+            Ok::<C::Value, std::convert::Infallible>(f())
+        }) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like [`SharedCache::get_or_insert_with`], but for making values in a
+    /// way that can fail. The error goes back to the thread whose `f`
+    /// failed, and nothing is inserted. Threads which were waiting on it run
+    /// their own `f` in turn, one at a time, rather than all receiving the
+    /// same error.
+    pub fn try_get_or_insert_with<F, E>(
+        &self,
+        k: C::Key,
+        f: F,
+    ) -> Result<C::Value, E>
+    where
+        F: FnOnce() -> Result<C::Value, E>,
+    {
+        if let Some(v) = self.get(&k) {
+            return Ok(v);
+        }
+
+        // only the first look counts towards the stats, as whatever
+        // happens next this was a miss
+        loop {
+            let _flight = match self.flights.board(&k) {
+                Boarding::Leader(flight) => flight,
+                Boarding::Follower(landing) => {
+                    landing.wait();
+                    if let Some(v) = self.lookup(&k, false) {
+                        return Ok(v);
+                    }
+                    continue;
+                }
+            };
+
+            // someone may have finished loading it in between missing
+            // it and boarding
+            if let Some(v) = self.lookup(&k, false) {
+                return Ok(v);
+            }
+
+            #[cfg(feature = "tracing")]
+            let tracer = self.tracer.load_full();
+            #[cfg(feature = "tracing")]
+            let _load = tracer.as_ref().map(|tracer| {
+                let hash_k = self.read().key_hash(&k);
+                tracer.load_span(Some(hash_k)).entered()
+            });
+
+            let started = Instant::now();
+            let loaded = f();
+            self.stats.add_load(loaded.is_ok(), started.elapsed());
+            #[cfg(feature = "tracing")]
+            if let Some(tracer) = &tracer {
+                tracer.loaded(loaded.is_ok(), started.elapsed());
+            }
+
+            let v = loaded?;
+            self.insert(k, v.clone());
+            return Ok(v);
+        }
+    }
+
+    /// Works out a new value for `k` from the current one, if there is
+    /// one, all under a single hold of the lock so that nobody else can
+    /// change it in between. Returning None removes the item. Gives back
+    /// the new value, if there is one.
+    ///
+    /// The new value goes in just like [`SharedCache::insert`], so it
+    /// counts as a write as far as an ExpiringCache is concerned.
+    pub fn compute<F>(&self, k: C::Key, f: F) -> Option<C::Value>
+    where
+        F: FnOnce(Option<&C::Value>) -> Option<C::Value>,
+    {
+        let mut cache = self.write();
+
+        let v = match f(cache.get(&k)) {
+            Some(v) => {
+                self.insert_into(&mut cache, k, v.clone());
+                Some(v)
+            }
+            None => {
+                self.removed(cache.remove(&k));
+                None
+            }
+        };
+
+        drop(cache);
+        v
+    }
+
+    /// Like [`SharedCache::compute`], but only for items which are already
+    /// in the cache; `f` isn't called for missing ones. The new value
+    /// replaces the old one in place, so as far as an ExpiringCache is
+    /// concerned this counts as a read rather than a write.
+    pub fn compute_if_present<Q, F>(
+        &self,
+        k: &Q,
+        f: F,
+    ) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&C::Value) -> Option<C::Value>,
+    {
+        let mut cache = self.write();
+        let v = match cache.get_mut(k) {
+            Some(v) => v,
+            None => {
+                drop(cache);
+                return None;
+            }
+        };
+
+        let v = match f(v) {
+            Some(new_v) => {
+                *v = new_v.clone();
+                self.stats.add(Counter::Updates, 1);
+                Some(new_v)
+            }
+            None => {
+                self.removed(cache.remove(k));
+                None
+            }
+        };
+
+        drop(cache);
+        v
+    }
+
+    /// Replaces an item already in the cache with whatever `f` makes of
+    /// it, giving back the new value. Does nothing for missing items.
+    pub fn update<Q, F>(&self, k: &Q, f: F) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&C::Value) -> C::Value,
+    {
+        self.compute_if_present(k, |v| Some(f(v)))
+    }
+
+    /// Changes an item already in the cache in place, giving back what it
+    /// is afterwards. Does nothing for missing items. Handy for values
+    /// like lists, which are cheaper to change than to make anew.
+    pub fn get_and_modify<Q, F>(&self, k: &Q, f: F) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&mut C::Value),
+    {
+        let mut cache = self.write();
+        let v = cache.get_mut(k).map(|v| {
+            f(v);
+            v.clone()
+        });
+
+        if v.is_some() {
+            self.stats.add(Counter::Updates, 1);
+        }
+        drop(cache);
+        v
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item.
+    pub fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.read().entry_info(k)
+    }
+
+    /// The hash the cache gives a key, which is how its events name it.
+    pub fn key_hash<Q>(&self, k: &Q) -> KeyHash
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.read().key_hash(k)
     }
 
     /// Remove an item from the cache, returning the removed item if it existed.
-    pub fn remove(&self, k: &K) -> Option<V> {
-        self.0.write().remove(k)
+    pub fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.removed(self.write().remove(k))
+    }
+
+    /// Like [`SharedCache::remove`], but gives up straight away rather than
+    /// wait if somebody else has the lock.
+    pub fn try_remove<Q>(
+        &self,
+        k: &Q,
+    ) -> Result<Option<C::Value>, WouldBlock>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        Ok(self.removed(self.write_within(None)?.remove(k)))
+    }
+
+    /// Like [`SharedCache::remove`], but gives up rather than wait any
+    /// longer than `timeout` if somebody else has the lock.
+    pub fn try_remove_timeout<Q>(
+        &self,
+        k: &Q,
+        timeout: Duration,
+    ) -> Result<Option<C::Value>, WouldBlock>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        Ok(self.removed(self.write_within(Some(timeout))?.remove(k)))
     }
 
     /// Clears the cache.
     pub fn clear(&self) {
-        self.0.write().clear()
+        let mut cache = self.write();
+        self.stats.add(Counter::Cleared, cache.len() as u64);
+        cache.clear()
     }
 
     /// The number of elements in the cache at present.
     pub fn len(&self) -> usize {
-        self.0.read().len()
+        self.cache.read().len()
+    }
+
+    /// Whether the cache is empty at present.
+    pub fn is_empty(&self) -> bool {
+        self.cache.read().is_empty()
+    }
+
+    /// Starts or stops keeping [`CacheStats`] for this cache, and every
+    /// clone of it, along with the cache inside. They are off to begin
+    /// with.
+    pub fn set_record_stats(&self, record: bool) {
+        self.stats.set_recording(record);
+        self.write().set_record_stats(record);
+    }
+
+    /// What the cache has been up to since it started recording stats, or
+    /// None if it isn't recording them. Gets, writes, removals and loads
+    /// are counted by the SharedCache, so gets which miss because an item
+    /// expired count as misses. Evictions, expirations and the age
+    /// histograms only the cache inside knows about, so they come from it
+    /// and stay at zero if it doesn't keep stats; gets on a cache made
+    /// with [`SharedCache::with_buffered_reads`] peek, so they don't show
+    /// up in its hit ages.
+    pub fn stats(&self) -> Option<CacheStats> {
+        let mut stats = self.stats.snapshot()?;
+
+        if let Some(inner) = self.cache.read().stats() {
+            stats.capacity_evictions = inner.capacity_evictions;
+            stats.expirations = inner.expirations;
+            stats.eviction_ages = inner.eviction_ages;
+            stats.expiry_ages = inner.expiry_ages;
+            stats.hit_ages = inner.hit_ages;
+        }
+
+        Some(stats)
+    }
+
+    /// Sets every stat back to zero, along with those of the cache inside.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+        self.write().reset_stats();
+    }
+
+    /// Subscribes to the events of the cache inside. Reads made through a
+    /// SharedCache with buffered reads only show up as accessed once they
+    /// are replayed, and some may not show up at all.
+    pub fn subscribe(
+        &self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> CacheEvents
+    where
+        C: EventSource,
+    {
+        self.write().subscribe(capacity, overflow)
+    }
+
+    /// Has a [`HotKeys`] tracker count every key looked up through this
+    /// cache or any clone of it from now on, replacing any which was
+    /// already attached. Keys are hashed for it while the lock is held
+    /// for the lookup anyway, and only while one is attached.
+    pub fn attach_hot_keys(&self, hot_keys: HotKeys<C::Key>) {
+        self.hot_keys.store(Some(Arc::new(hot_keys)));
+    }
+
+    /// Stops the attached [`HotKeys`] tracker counting lookups, and hands
+    /// it back.
+    pub fn detach_hot_keys(&self) -> Option<HotKeys<C::Key>> {
+        self.hot_keys.swap(None).map(|hot_keys| {
+            Arc::try_unwrap(hot_keys).unwrap_or_else(|h| (*h).clone())
+        })
+    }
+
+    /// Has the cache inside and this one report what they do through a
+    /// [`CacheTracer`], from now on, replacing any which was already
+    /// attached. The cache inside reports misses, evictions and expiry,
+    /// while this one reports loads made by
+    /// [`SharedCache::get_or_insert_with`] and how long each wait for the
+    /// lock took. Gets on a cache made with
+    /// [`SharedCache::with_buffered_reads`] are looked up with a peek, so
+    /// their misses aren't reported.
+    #[cfg(feature = "tracing")]
+    pub fn attach_tracer(&self, tracer: CacheTracer)
+    where
+        C: Traceable,
+    {
+        self.write().attach_tracer(tracer.clone());
+        self.tracer.store(Some(Arc::new(tracer)));
+    }
+
+    /// Stops reporting through the attached [`CacheTracer`], and hands it
+    /// back.
+    #[cfg(feature = "tracing")]
+    pub fn detach_tracer(&self) -> Option<CacheTracer>
+    where
+        C: Traceable,
+    {
+        self.write().detach_tracer();
+        self.tracer.swap(None).map(|tracer| {
+            Arc::try_unwrap(tracer).unwrap_or_else(|t| (*t).clone())
+        })
+    }
+
+    /// Counts a lookup towards the hot keys, if they're tracked, only
+    /// hashing the key with `hash_k` if they are.
+    fn observe(&self, hash_k: impl FnOnce() -> KeyHash) {
+        if let Some(hot_keys) = &*self.hot_keys.load() {
+            hot_keys.observe(hash_k());
+        }
+    }
+
+    /// Gets an item without counting it towards the stats, and only
+    /// towards the hot keys if `observe` says so.
+    fn lookup<Q>(&self, k: &Q, observe: bool) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match &self.read_buffer {
+            Some(read_buffer) => {
+                self.get_buffered(self.read(), read_buffer, k, observe)
+            }
+            None => {
+                let mut cache = self.write();
+                if observe {
+                    self.observe(|| cache.key_hash(k));
+                }
+                let v = cache.get(k).cloned();
+                drop(cache);
+                v
+            }
+        }
+    }
+
+    /// Inserts into a cache which is already locked, counting what
+    /// happened. An insert which didn't replace anything and didn't grow
+    /// the cache must have evicted something to make room.
+    fn insert_into(
+        &self,
+        cache: &mut C,
+        k: C::Key,
+        v: C::Value,
+    ) -> Option<C::Value> {
+        if let Some(hot_keys) = &*self.hot_keys.load() {
+            hot_keys.written(cache.key_hash(&k), &k);
+        }
+
+        let old_v = cache.insert(k, v);
+
+        match old_v {
+            Some(_) => self.stats.add(Counter::Updates, 1),
+            None => self.stats.add(Counter::Inserts, 1),
+        }
+
+        old_v
+    }
+
+    fn record_lookup(&self, found: bool) {
+        match found {
+            true => self.stats.add(Counter::Hits, 1),
+            false => self.stats.add(Counter::Misses, 1),
+        }
+    }
+
+    fn removed(&self, v: Option<C::Value>) -> Option<C::Value> {
+        if v.is_some() {
+            self.stats.add(Counter::Removals, 1);
+        }
+        v
+    }
+
+    /// Takes the lock to write, first catching the cache up on any reads
+    /// which were buffered.
+    fn write(&self) -> RwLockWriteGuard<'_, C> {
+        let mut cache = self.wait_for(true, || self.cache.write());
+        self.replay_reads(&mut cache);
+        cache
+    }
+
+    /// Takes the lock to read.
+    fn read(&self) -> RwLockReadGuard<'_, C> {
+        self.wait_for(false, || self.cache.read())
+    }
+
+    /// Takes a lock to read or write with `lock`, noting how long it took
+    /// if there is a tracer to tell.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn wait_for<T>(&self, write: bool, lock: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        if let Some(tracer) = &*self.tracer.load() {
+            let started = Instant::now();
+            let guard = lock();
+            tracer.lock_waited(write, started.elapsed());
+            return guard;
+        }
+
+        lock()
+    }
+
+    /// Takes the lock to write like [`SharedCache::write`], waiting for
+    /// it no longer than `timeout`, or not at all if there isn't one.
+    fn write_within(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<RwLockWriteGuard<'_, C>, WouldBlock> {
+        let cache = self.wait_for(true, || match timeout {
+            Some(timeout) => self.cache.try_write_for(timeout),
+            None => self.cache.try_write(),
+        });
+        let mut cache = cache.ok_or(WouldBlock(()))?;
+        self.replay_reads(&mut cache);
+        Ok(cache)
+    }
+
+    fn get_within<Q>(
+        &self,
+        k: &Q,
+        timeout: Option<Duration>,
+    ) -> Result<Option<C::Value>, WouldBlock>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let read_buffer = match &self.read_buffer {
+            Some(read_buffer) => read_buffer,
+            None => {
+                let mut cache = self.write_within(timeout)?;
+                self.observe(|| cache.key_hash(k));
+                let v = cache.get(k).cloned();
+                drop(cache);
+                self.record_lookup(v.is_some());
+                return Ok(v);
+            }
+        };
+
+        let cache = self.wait_for(false, || match timeout {
+            Some(timeout) => self.cache.try_read_for(timeout),
+            None => self.cache.try_read(),
+        });
+        let cache = cache.ok_or(WouldBlock(()))?;
+        let v = self.get_buffered(cache, read_buffer, k, true);
+        self.record_lookup(v.is_some());
+        Ok(v)
+    }
+
+    fn insert_within(
+        &self,
+        k: C::Key,
+        v: C::Value,
+        timeout: Option<Duration>,
+    ) -> Result<Option<C::Value>, Rejected<C>> {
+        match self.write_within(timeout) {
+            Ok(mut cache) => {
+                let old_v = self.insert_into(&mut cache, k, v);
+                drop(cache);
+                Ok(old_v)
+            }
+            Err(_) => Err(WouldBlock((k, v))),
+        }
+    }
+
+    /// Gets an item under a shared lock, writing the read down to be
+    /// replayed later, and counting it towards the hot keys if `observe`
+    /// says so. The key is hashed at most once for the both of them.
+    fn get_buffered<Q>(
+        &self,
+        cache: RwLockReadGuard<'_, C>,
+        read_buffer: &ReadBuffer,
+        k: &Q,
+        observe: bool,
+    ) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = cache.peek(k).cloned();
+        let hot_keys = match observe {
+            true => self.hot_keys.load_full(),
+            false => None,
+        };
+
+        let mut full = false;
+        if v.is_some() || hot_keys.is_some() {
+            let hash_k = cache.key_hash(k);
+            if let Some(hot_keys) = &hot_keys {
+                hot_keys.observe(hash_k);
+            }
+            full = v.is_some() && read_buffer.record(hash_k);
+        }
+        drop(cache);
+
+        // don't hold up the reader if someone else has the lock, they'll
+        // get around to draining the buffer soon enough
+        if full {
+            if let Some(mut cache) = self.cache.try_write() {
+                self.replay_reads(&mut cache);
+                drop(cache);
+            }
+        }
+
+        v
+    }
+
+    fn replay_reads(&self, cache: &mut C) {
+        if let Some(read_buffer) = &self.read_buffer {
+            read_buffer.drain(|hash_k| cache.touch_hash(hash_k));
+        }
     }
 }
 
-impl<C, K, V> Clone for SharedCache<C, K, V>
+impl<K, V, S> SharedCache<ExpiringCache<K, V, S>>
 where
-    C: Cache<K, V>,
-    K: Eq + Hash,
-    V: Clone,
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    /// Starts a thread which purges expired entries from the cache every
+    /// `interval`, since an ExpiringCache never evicts anything on its
+    /// own. Expired entries are removed a few at a time so the lock is
+    /// only ever held briefly. The thread stops once every handle to the
+    /// cache has been dropped, or when told to with [`Reaper::shutdown`].
+    pub fn spawn_reaper(&self, interval: Duration) -> Reaper {
+        Reaper::spawn(Arc::downgrade(&self.cache), interval)
+    }
+}
+
+impl<C> ConcurrentCache for SharedCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    type Key = C::Key;
+    type Value = C::Value;
+
+    fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        SharedCache::insert(self, k, v)
+    }
+
+    fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        SharedCache::get(self, k)
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        SharedCache::entry_info(self, k)
+    }
+
+    fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        SharedCache::remove(self, k)
+    }
+
+    fn clear(&self) {
+        SharedCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        SharedCache::len(self)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<C> MeteredCache for SharedCache<C>
+where
+    C: Cache + MeteredCache,
+    C::Value: Clone,
+{
+    /// Stats come from the SharedCache if it is recording them, and from
+    /// the cache inside otherwise.
+    fn metrics(&self) -> CacheMetrics {
+        let inner = self.cache.read().metrics();
+
+        CacheMetrics {
+            stats: self.stats().or(inner.stats),
+            ..inner
+        }
+    }
+}
+
+/// Shows the cache inside, unless somebody has the lock to write, in
+/// which case it shows that it's locked rather than wait.
+impl<C> fmt::Debug for SharedCache<C>
+where
+    C: Cache + fmt::Debug,
+    C::Value: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SharedCache");
+        match self.cache.try_read() {
+            Some(cache) => d.field("cache", &*cache),
+            None => d.field("cache", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+/// Takes the lock to read for as long as the dump takes.
+#[cfg(feature = "serde")]
+impl<C> crate::json::JsonDump for SharedCache<C>
+where
+    C: Cache + crate::json::JsonDump,
+    C::Value: Clone,
+{
+    fn to_json(&self) -> serde_json::Value {
+        self.cache.read().to_json()
+    }
+}
+
+impl<C> Clone for SharedCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
 {
     fn clone(&self) -> Self {
-        SharedCache(self.0.clone(), PhantomData, PhantomData)
+        SharedCache {
+            cache: self.cache.clone(),
+            read_buffer: self.read_buffer.clone(),
+            flights: self.flights.clone(),
+            stats: self.stats.clone(),
+            hot_keys: self.hot_keys.clone(),
+            #[cfg(feature = "tracing")]
+            tracer: self.tracer.clone(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{thread, time::Duration};
+    use std::{
+        borrow::Borrow,
+        collections::HashMap,
+        error::Error,
+        hash::Hash,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Barrier, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
 
-    use crate::{ExpiringCache, LruCache, SharedCache};
+    use crate::{
+        null_hasher::BuildNullHasher, Cache, CacheEvent,
+        ConcurrentCache, ExpiringCache, HotKeys, Loader, LruCache,
+        Overflow, SharedCache, WouldBlock,
+    };
 
     #[test]
     fn readme_snippet() {
-        let cache: SharedCache<LruCache<usize, usize>, usize, usize> =
+        let cache: SharedCache<LruCache<usize, usize>> =
             SharedCache::with_cache(LruCache::with_capacity(1));
         cache.insert(1, 1);
 
@@ -85,17 +944,601 @@ mod tests {
 
     #[test]
     fn expiring_cache_test() {
-        let cache: SharedCache<
-            ExpiringCache<usize, usize>,
-            usize,
-            usize,
-        > = SharedCache::with_cache(
-            ExpiringCache::with_capacity_and_timeout(
-                1,
-                Duration::from_secs(30),
-            ),
+        let cache: SharedCache<ExpiringCache<usize, usize>> =
+            SharedCache::with_cache(
+                ExpiringCache::with_capacity_and_timeout(
+                    1,
+                    Duration::from_secs(30),
+                ),
+            );
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+    }
+
+    #[test]
+    fn test_buffered_reads() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_buffered_reads(LruCache::with_capacity(
+                2,
+            ));
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        // the read of 1 is replayed before 3 goes in, so 2 is evicted
+        assert_eq!(Some(1), cache.get(&1));
+        cache.insert(3, 3);
+        assert_eq!(None, cache.get(&2));
+        assert_eq!(Some(1), cache.get(&1));
+        assert_eq!(Some(3), cache.get(&3));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        assert_eq!(Some(1), cache.get(&1));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(2, cache.len());
+    }
+
+    #[derive(Clone)]
+    struct Document {
+        title: String,
+        body: Vec<u8>,
+    }
+
+    #[test]
+    fn test_get_ref() {
+        let cache: SharedCache<LruCache<usize, Document>> =
+            SharedCache::with_cache(LruCache::with_capacity(2));
+        for k in 1..=2 {
+            cache.insert(
+                k,
+                Document {
+                    title: format!("document {}", k),
+                    body: vec![0; 1 << 20],
+                },
+            );
+        }
+
+        assert_eq!("document 1", cache.get_ref(&1).unwrap().title);
+        cache.get_ref(&1).unwrap().title.push('!');
+        assert_eq!(1 << 20, cache.peek_ref(&2).unwrap().body.len());
+        assert!(cache.get_ref(&3).is_none());
+        assert!(cache.peek_ref(&3).is_none());
+
+        // peeking at 2 didn't save it, but getting 1 did
+        cache.insert(3, cache.get(&1).unwrap());
+        assert!(cache.peek_ref(&2).is_none());
+        assert_eq!("document 1!", cache.peek_ref(&1).unwrap().title);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                let loads = loads.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with(1, || {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        42
+                    })
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(42, thread.join().unwrap());
+        }
+        assert_eq!(1, loads.load(Ordering::SeqCst));
+        assert_eq!(Some(42), cache.get(&1));
+    }
+
+    #[test]
+    fn test_get_or_insert_with_panic() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+
+        let (loading_tx, loading_rx) = mpsc::channel();
+        let panicking_cache = cache.clone();
+        let panicked = thread::spawn(move || {
+            panicking_cache.get_or_insert_with(1, || {
+                loading_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                panic!("failed to load")
+            })
+        });
+
+        // wait until the other thread is loading so this one waits on it
+        loading_rx.recv().unwrap();
+        assert_eq!(2, cache.get_or_insert_with(1, || 2));
+        assert!(panicked.join().is_err());
+        assert_eq!(Some(2), cache.get(&1));
+    }
+
+    #[test]
+    fn test_try_get_or_insert_with() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+
+        assert_eq!(
+            Err("failed to load"),
+            cache.try_get_or_insert_with(1, || Err("failed to load"))
+        );
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(
+            Ok(1),
+            cache.try_get_or_insert_with(1, || Ok::<_, ()>(1))
+        );
+        assert_eq!(Ok(1), cache.try_get_or_insert_with(1, || Err(())));
+    }
+
+    #[test]
+    fn test_compute() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        cache.compute(1, |n| Some(n.unwrap_or(&0) + 1));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(Some(4000), cache.get(&1));
+
+        assert_eq!(None, cache.compute(1, |_| None));
+        assert_eq!(None, cache.get(&1));
+
+        assert_eq!(None, cache.compute_if_present(&2, |n| Some(n + 1)));
+        assert_eq!(None, cache.get(&2));
+        cache.insert(2, 2);
+        assert_eq!(
+            Some(3),
+            cache.compute_if_present(&2, |n| Some(n + 1))
+        );
+        assert_eq!(None, cache.compute_if_present(&2, |_| None));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_update_and_get_and_modify() {
+        let cache: SharedCache<LruCache<usize, Vec<usize>>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+
+        assert_eq!(None, cache.update(&1, |v| v.clone()));
+        assert_eq!(None, cache.get_and_modify(&1, |v| v.push(1)));
+
+        cache.insert(1, vec![1]);
+        assert_eq!(
+            Some(vec![1, 2]),
+            cache.get_and_modify(&1, |v| v.push(2))
+        );
+        assert_eq!(
+            Some(vec![2, 4]),
+            cache.update(&1, |v| v.iter().map(|n| n * 2).collect())
+        );
+        assert_eq!(Some(vec![2, 4]), cache.get(&1));
+    }
+
+    #[test]
+    fn test_try_operations() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        assert_eq!(Ok(None), cache.try_insert(1, 1));
+
+        let held = cache.peek_ref(&1);
+        assert_eq!(Err(WouldBlock(())), cache.try_get(&1));
+        assert_eq!(Err(WouldBlock((2, 2))), cache.try_insert(2, 2));
+        assert_eq!(
+            Err(WouldBlock(())),
+            cache.try_remove_timeout(&1, Duration::from_millis(10))
+        );
+        drop(held);
+
+        assert_eq!(
+            Ok(Some(1)),
+            cache.try_get_timeout(&1, Duration::from_millis(10))
+        );
+        assert_eq!(
+            Ok(Some(1)),
+            cache.try_insert_timeout(1, 2, Duration::from_millis(10))
+        );
+        assert_eq!(Ok(Some(2)), cache.try_remove(&1));
+    }
+
+    #[test]
+    fn test_try_get_with_buffered_reads() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_buffered_reads(LruCache::with_capacity(
+                10,
+            ));
+        cache.insert(1, 1);
+
+        // readers share the lock, so only a writer gets in the way
+        let reading = cache.peek_ref(&1);
+        assert_eq!(Ok(Some(1)), cache.try_get(&1));
+        drop(reading);
+
+        let writing = cache.get_ref(&1);
+        assert_eq!(
+            Err(WouldBlock(())),
+            cache.try_get_timeout(&1, Duration::from_millis(10))
+        );
+        drop(writing);
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let cache: SharedCache<LruCache<String, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        cache.insert("one".to_owned(), 1);
+        assert_eq!(Some(1), cache.get("one"));
+
+        // and through the trait, as code which works with any cache would
+        fn lookup<C>(cache: &C, word: &str) -> Option<usize>
+        where
+            C: ConcurrentCache<Key = String, Value = usize>,
+        {
+            cache.get(word)
+        }
+        assert_eq!(Some(1), lookup(&cache, "one"));
+
+        assert_eq!(Some(1), cache.remove("one"));
+        assert!(cache.is_empty());
+    }
+
+    /// A cache which implements only what it has to, like one from
+    /// outside the crate might.
+    struct MapCache(HashMap<usize, usize>);
+
+    impl Cache for MapCache {
+        type Key = usize;
+        type Value = usize;
+
+        fn insert(&mut self, k: usize, v: usize) -> Option<usize> {
+            self.0.insert(k, v)
+        }
+
+        fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut usize>
+        where
+            usize: Borrow<Q>,
+            Q: Hash + Eq + ?Sized,
+        {
+            self.0.get_mut(k)
+        }
+
+        fn remove<Q>(&mut self, k: &Q) -> Option<usize>
+        where
+            usize: Borrow<Q>,
+            Q: Hash + Eq + ?Sized,
+        {
+            self.0.remove(k)
+        }
+
+        fn clear(&mut self) {
+            self.0.clear()
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn test_minimal_cache() {
+        let cache = SharedCache::with_cache(MapCache(HashMap::new()));
+        cache.attach_hot_keys(HotKeys::new(4, Duration::from_secs(60)));
+
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+        assert_eq!(Some(2), cache.update(&1, |v| v + 1));
+        assert_eq!(Some(2), cache.remove(&1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_buffered_reads_without_peek() {
+        let cache =
+            SharedCache::with_buffered_reads(MapCache(HashMap::new()));
+
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+        assert_eq!(Ok(Some(1)), cache.try_get(&1));
+        assert_eq!(1, cache.get_or_insert_with(1, || 2));
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_buffered_reads(LruCache::with_capacity(
+                2,
+            ));
+        assert_eq!(None, cache.stats());
+        cache.clone().set_record_stats(true);
+
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(Some(3), cache.get(&3));
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(4, cache.get_or_insert_with(4, || 4));
+        assert_eq!(
+            Err(()),
+            cache.try_get_or_insert_with(5, || Err::<usize, ()>(()))
         );
+        assert_eq!(Some(4), cache.remove(&4));
+        cache.clear();
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(1, stats.hits);
+        assert_eq!(3, stats.misses);
+        assert_eq!(4, stats.inserts);
+        assert_eq!(1, stats.updates);
+        assert_eq!(2, stats.capacity_evictions);
+        assert_eq!(1, stats.removals);
+        assert_eq!(1, stats.cleared);
+        assert_eq!(1, stats.load_successes);
+        assert_eq!(1, stats.load_failures);
+
+        cache.reset_stats();
+        assert_eq!(Some(Default::default()), cache.stats());
+    }
+
+    #[test]
+    fn test_stats_from_inner_cache() {
+        let cache: SharedCache<ExpiringCache<usize, usize>> =
+            SharedCache::with_cache(
+                ExpiringCache::with_capacity_and_timeout(
+                    2,
+                    Duration::from_millis(10),
+                ),
+            );
+        cache.set_record_stats(true);
+
+        // nothing is evicted, and the item found to have expired is
+        // counted by the cache inside
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(2, 3);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, cache.get(&1));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(0, stats.capacity_evictions);
+        assert_eq!(1, stats.expirations);
+        assert_eq!(1, stats.expiry_ages.count());
+        assert_eq!(1, stats.misses);
+    }
+
+    #[test]
+    fn test_age_histograms() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(1));
+        cache.set_record_stats(true);
+
         cache.insert(1, 1);
         assert_eq!(Some(1), cache.get(&1));
+        cache.insert(2, 2);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(1, stats.hit_ages.count());
+        assert_eq!(1, stats.eviction_ages.count());
+
+        cache.reset_stats();
+        assert!(cache.stats().unwrap().hit_ages.is_empty());
+    }
+
+    #[test]
+    fn test_entry_info() {
+        let mut lru = LruCache::with_capacity(10);
+        lru.set_track_metadata(true);
+        let cache: SharedCache<LruCache<&str, usize>> =
+            SharedCache::with_cache(lru);
+
+        cache.insert("a", 1);
+        cache.get("a");
+        assert_eq!(
+            Some(1),
+            cache.entry_info("a").unwrap().access_count
+        );
+        assert_eq!(None, ConcurrentCache::entry_info(&cache, "b"));
+    }
+
+    #[test]
+    fn test_debug() {
+        let cache: SharedCache<LruCache<&str, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        cache.insert("a", 1);
+        assert!(format!("{:?}", cache).starts_with(
+            "SharedCache { cache: LruCache { capacity: 10,"
+        ));
+
+        let _writing = cache.cache.write();
+        assert_eq!(
+            "SharedCache { cache: <locked> }",
+            format!("{:?}", cache)
+        );
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let cache: SharedCache<LruCache<u64, u64, BuildNullHasher>> =
+            SharedCache::with_buffered_reads(
+                LruCache::with_capacity_and_hash_builder(
+                    10,
+                    BuildNullHasher,
+                ),
+            );
+        let hot_keys = HotKeys::new(1, Duration::from_secs(60));
+        cache.attach_hot_keys(hot_keys.clone());
+
+        cache.insert(7, 7);
+        for k in 0..5 {
+            cache.get(&7);
+            assert_eq!(Ok(None), cache.try_get(&k));
+        }
+
+        // with a null hasher each key is its own hash
+        let top = hot_keys.top();
+        assert_eq!(7, top[0].hash);
+        assert_eq!(Some(7), top[0].key);
+        assert!(cache.detach_hot_keys().is_some());
+
+        // and nothing more is counted once it's gone
+        cache.get(&7);
+        assert_eq!(top, hot_keys.top());
+    }
+
+    #[test]
+    fn test_events() {
+        let cache: SharedCache<LruCache<usize, usize>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        let events = cache.subscribe(16, Overflow::DropNewest);
+
+        let writer = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.insert(1, 1);
+                cache.get(&1);
+                cache.remove(&1);
+            })
+        };
+        writer.join().unwrap();
+
+        let received: Vec<_> =
+            (0..3).filter_map(|_| events.recv()).collect();
+        assert!(matches!(
+            received[..],
+            [
+                CacheEvent::Inserted(a),
+                CacheEvent::Accessed(b),
+                CacheEvent::Removed(c),
+            ] if a == b && b == c
+        ));
+
+        // the subscription ends once every clone of the cache is gone
+        drop(cache);
+        assert_eq!(None, events.recv());
+    }
+
+    /// Waits up to a second for something to become true.
+    fn eventually(f: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Says when it starts a reload, then waits to be let go.
+    struct GatedLoader {
+        started: Mutex<mpsc::Sender<()>>,
+        go: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Loader<usize, usize> for GatedLoader {
+        fn reload(
+            &self,
+            _k: &usize,
+            old: &usize,
+        ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+            let _ = self.started.lock().unwrap().send(());
+            let _ = self.go.lock().unwrap().recv();
+            Ok(old + 1)
+        }
+    }
+
+    #[test]
+    fn test_reload_outside_lock() {
+        let (started_tx, started) = mpsc::channel();
+        let (go, go_rx) = mpsc::channel();
+        let mut inner = ExpiringCache::with_capacity_and_timeout(
+            10,
+            Duration::from_secs(30),
+        );
+        inner.set_refresh_after_write(
+            Duration::ZERO,
+            GatedLoader {
+                started: Mutex::new(started_tx),
+                go: Mutex::new(go_rx),
+            },
+        );
+        let cache: SharedCache<ExpiringCache<usize, usize>> =
+            SharedCache::with_cache(inner);
+        cache.insert(1, 1);
+
+        // the get which set the reload off doesn't wait for it
+        assert_eq!(Some(1), cache.get(&1));
+        started.recv().unwrap();
+
+        // the reload is underway, but the lock is free and the entry
+        // isn't reloaded a second time
+        assert_eq!(Ok(Some(1)), cache.try_get(&1));
+        assert!(started.try_recv().is_err());
+        go.send(()).unwrap();
+        drop(go);
+
+        assert!(eventually(|| cache.get(&1) == Some(2)));
+    }
+
+    #[test]
+    fn test_reaper() {
+        let cache: SharedCache<ExpiringCache<usize, usize>> =
+            SharedCache::with_cache(
+                ExpiringCache::with_capacity_and_timeout(
+                    10,
+                    Duration::from_millis(5),
+                ),
+            );
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        let reaper = cache.spawn_reaper(Duration::from_millis(1));
+        assert!(eventually(|| cache.is_empty()));
+        assert!(reaper.is_running());
+
+        reaper.shutdown();
+    }
+
+    #[test]
+    fn test_reaper_stops_with_cache() {
+        let cache: SharedCache<ExpiringCache<usize, usize>> =
+            SharedCache::with_cache(
+                ExpiringCache::with_capacity_and_timeout(
+                    10,
+                    Duration::from_secs(30),
+                ),
+            );
+        let reaper = cache.spawn_reaper(Duration::from_millis(1));
+        cache.insert(1, 1);
+
+        drop(cache);
+        assert!(eventually(|| !reaper.is_running()));
     }
 }
@@ -0,0 +1,147 @@
+//! Makes sure only one thread or task at a time loads the value for a key,
+//! with any others who want the same key waiting for it to finish. Threads
+//! wait by blocking and tasks by awaiting, on the same flights.
+
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    future::Future,
+    hash::{BuildHasher, Hash},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{cache::KeyHash, null_hasher::BuildNullHasher};
+
+/// A load which is underway.
+struct Flight {
+    state: Mutex<FlightState>,
+    landed: Condvar,
+}
+
+/// Whether a load is done, and the tasks waiting for it to be. Threads
+/// waiting for it wait on the condvar instead.
+struct FlightState {
+    done: bool,
+    waiting: Vec<Waker>,
+}
+
+/// Every load which is underway, by the hash of its key. Keys which share
+/// a hash share a flight, which only means they wait a little longer than
+/// they strictly need to.
+pub(crate) struct Flights {
+    in_flight: Mutex<HashMap<KeyHash, Arc<Flight>, BuildNullHasher>>,
+    hash_builder: RandomState,
+}
+
+/// What a thread or task which asked to load a key should do about it.
+pub(crate) enum Boarding<'a> {
+    /// Nobody else is loading the key, so this one should. Everyone
+    /// waiting on it is woken once this is dropped, however the load goes.
+    Leader(FlightGuard<'a>),
+    /// Somebody else is loading the key. Once they've finished, one way or
+    /// another, this one should look in the cache again.
+    Follower(Landing),
+}
+
+/// Wakes everyone waiting on a load when it is dropped, which happens
+/// whether the load finished, failed, panicked, or the future doing it
+/// was dropped part way through.
+pub(crate) struct FlightGuard<'a> {
+    flights: &'a Flights,
+    hash_k: KeyHash,
+    flight: Arc<Flight>,
+}
+
+/// A load somebody else is doing, which can be waited on by blocking or
+/// awaited.
+pub(crate) struct Landing(Arc<Flight>);
+
+impl Flights {
+    pub(crate) fn new() -> Self {
+        Flights {
+            in_flight: Mutex::new(HashMap::with_hasher(
+                BuildNullHasher,
+            )),
+            hash_builder: RandomState::new(),
+        }
+    }
+
+    /// Either makes the caller the one to load `k`, or hands back the load
+    /// which is already underway to wait for.
+    pub(crate) fn board<K>(&self, k: &K) -> Boarding<'_>
+    where
+        K: Hash + ?Sized,
+    {
+        let hash_k = self.hash_builder.hash_one(k);
+
+        let mut in_flight = self.in_flight.lock();
+        if let Some(flight) = in_flight.get(&hash_k) {
+            return Boarding::Follower(Landing(flight.clone()));
+        }
+
+        let flight = Arc::new(Flight {
+            state: Mutex::new(FlightState {
+                done: false,
+                waiting: Vec::new(),
+            }),
+            landed: Condvar::new(),
+        });
+        in_flight.insert(hash_k, flight.clone());
+
+        Boarding::Leader(FlightGuard {
+            flights: self,
+            hash_k,
+            flight,
+        })
+    }
+}
+
+impl Drop for FlightGuard<'_> {
+    // this runs when the loader panics too, which is what keeps a panic
+    // from leaving everyone else waiting forever
+    fn drop(&mut self) {
+        self.flights.in_flight.lock().remove(&self.hash_k);
+
+        let waiting = {
+            let mut state = self.flight.state.lock();
+            state.done = true;
+            std::mem::take(&mut state.waiting)
+        };
+
+        self.flight.landed.notify_all();
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+impl Landing {
+    /// Blocks the thread until the load is done.
+    #[cfg(feature = "shared_cache")]
+    pub(crate) fn wait(self) {
+        let mut state = self.0.state.lock();
+        while !state.done {
+            self.0.landed.wait(&mut state);
+        }
+    }
+}
+
+impl Future for Landing {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.state.lock();
+
+        if state.done {
+            return Poll::Ready(());
+        }
+
+        if !state.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiting.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,415 @@
+//! Counts of what a cache has been up to, for telling whether it is sized
+//! and configured sensibly.
+
+use std::{convert::TryFrom, fmt, time::Duration};
+
+#[cfg(feature = "shared_cache")]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// A snapshot of what a cache has been up to since it started recording
+/// statistics, or since they were last reset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Gets which found what they were looking for.
+    pub hits: u64,
+    /// Gets which came up empty.
+    pub misses: u64,
+    /// Items inserted under a key which wasn't in the cache.
+    pub inserts: u64,
+    /// Items inserted under a key which was, replacing what was there.
+    pub updates: u64,
+    /// Items evicted to make room for others. One which had expired by
+    /// then counts among the expirations instead.
+    pub capacity_evictions: u64,
+    /// Items taken out of the cache on purpose with a remove.
+    pub removals: u64,
+    /// Items thrown out by clearing the cache.
+    pub cleared: u64,
+    /// Items thrown out because they had expired.
+    pub expirations: u64,
+    /// Values which were loaded or reloaded successfully.
+    pub load_successes: u64,
+    /// Loads or reloads which failed.
+    pub load_failures: u64,
+    /// How long all of the loads took put together, failures included.
+    pub total_load_time: Duration,
+    /// How long items had been in the cache when they were evicted to
+    /// make room for others.
+    pub eviction_ages: AgeHistogram,
+    /// How long items had been in the cache when they were found to have
+    /// expired.
+    pub expiry_ages: AgeHistogram,
+    /// How long items had been in the cache when a get found them.
+    pub hit_ages: AgeHistogram,
+}
+
+impl CacheStats {
+    /// How many gets there were all told.
+    pub fn requests(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// The fraction of gets which were hits, or zero if there haven't been
+    /// any gets yet.
+    pub fn hit_rate(&self) -> f64 {
+        match self.requests() {
+            0 => 0.0,
+            requests => self.hits as f64 / requests as f64,
+        }
+    }
+
+    /// Items the cache threw out by itself, whether to make room or
+    /// because they expired.
+    pub fn evictions(&self) -> u64 {
+        self.capacity_evictions + self.expirations
+    }
+
+    /// How long a load took on average, if there were any.
+    pub fn average_load_time(&self) -> Option<Duration> {
+        let loads = self.load_successes + self.load_failures;
+
+        match u32::try_from(loads) {
+            Ok(0) => None,
+            Ok(loads) => Some(self.total_load_time / loads),
+            Err(_) => Some(Duration::from_secs_f64(
+                self.total_load_time.as_secs_f64() / loads as f64,
+            )),
+        }
+    }
+
+    /// Adds another cache's stats to these, for caches made of several
+    /// others, such as the shards of a ShardedCache.
+    #[cfg(all(feature = "metrics", feature = "shared_cache"))]
+    pub(crate) fn add(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.inserts += other.inserts;
+        self.updates += other.updates;
+        self.capacity_evictions += other.capacity_evictions;
+        self.removals += other.removals;
+        self.cleared += other.cleared;
+        self.expirations += other.expirations;
+        self.load_successes += other.load_successes;
+        self.load_failures += other.load_failures;
+        self.total_load_time += other.total_load_time;
+        self.eviction_ages.add(&other.eviction_ages);
+        self.expiry_ages.add(&other.expiry_ages);
+        self.hit_ages.add(&other.hit_ages);
+    }
+}
+
+/// How many buckets an [`AgeHistogram`] has. The last one holds every age
+/// from about eight and a half years up.
+const AGE_BUCKETS: usize = 40;
+
+/// Counts of how old items were, in buckets which double in width, so
+/// that ages from a millisecond to years fit in a fixed amount of space
+/// to within a factor of two. The first bucket holds ages under a
+/// millisecond, and each one after holds ages from the end of the one
+/// before up to twice that.
+///
+/// Ages run from when an item was last written. An LruCache only keeps
+/// that while it is recording stats, so items it had from before then
+/// have no age.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AgeHistogram {
+    counts: [u64; AGE_BUCKETS],
+}
+
+impl AgeHistogram {
+    /// Counts one item of the given age.
+    pub(crate) fn record(&mut self, age: Duration) {
+        let millis = age.as_millis();
+        let bucket = match millis {
+            0 => 0,
+            millis => (128 - millis.leading_zeros()) as usize,
+        };
+
+        self.counts[bucket.min(AGE_BUCKETS - 1)] += 1;
+    }
+
+    /// Counts everything another histogram counted.
+    #[cfg(all(feature = "metrics", feature = "shared_cache"))]
+    pub(crate) fn add(&mut self, other: &AgeHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+    }
+
+    /// How many items were counted.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Whether nothing was counted.
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// The end of each bucket, which ages in it are under, along with how
+    /// many items were in it, youngest first. The last bucket never ends,
+    /// so it ends at [`Duration::MAX`].
+    pub fn buckets(
+        &self,
+    ) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, n)| {
+            let end = match i {
+                i if i == AGE_BUCKETS - 1 => Duration::MAX,
+                i => Duration::from_millis(1 << i),
+            };
+            (end, *n)
+        })
+    }
+
+    /// The age which the fraction `q` of the items, between 0 and 1, were
+    /// under, rounded up to the end of its bucket. None if nothing was
+    /// counted.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank =
+            ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        self.buckets().find_map(|(end, n)| {
+            seen += n;
+            Some(end).filter(|_| seen >= rank)
+        })
+    }
+}
+
+impl Default for AgeHistogram {
+    fn default() -> Self {
+        AgeHistogram {
+            counts: [0; AGE_BUCKETS],
+        }
+    }
+}
+
+/// Shows only the buckets which have something in them, by where they
+/// end.
+impl fmt::Debug for AgeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.buckets().filter(|(_, n)| *n > 0))
+            .finish()
+    }
+}
+
+/// Statistics for a cache which is only ever used from one place at a
+/// time, which are only kept once asked for.
+#[derive(Default)]
+pub(crate) struct StatsRecorder(Option<CacheStats>);
+
+impl StatsRecorder {
+    pub(crate) fn set_recording(&mut self, record: bool) {
+        match (record, &self.0) {
+            (true, None) => self.0 = Some(CacheStats::default()),
+            (false, _) => self.0 = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    pub(crate) fn record<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut CacheStats),
+    {
+        if let Some(stats) = &mut self.0 {
+            f(stats);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Option<CacheStats> {
+        self.0
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.record(|stats| *stats = CacheStats::default());
+    }
+}
+
+/// Statistics for a cache shared between threads, which can be recorded
+/// to from behind a shared lock.
+#[cfg(feature = "shared_cache")]
+#[derive(Default)]
+pub(crate) struct AtomicStats {
+    recording: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    updates: AtomicU64,
+    removals: AtomicU64,
+    cleared: AtomicU64,
+    load_successes: AtomicU64,
+    load_failures: AtomicU64,
+    total_load_nanos: AtomicU64,
+}
+
+/// Which of the counters in [`AtomicStats`] to add to.
+#[cfg(feature = "shared_cache")]
+#[derive(Clone, Copy)]
+pub(crate) enum Counter {
+    Hits,
+    Misses,
+    Inserts,
+    Updates,
+    Removals,
+    Cleared,
+}
+
+#[cfg(feature = "shared_cache")]
+impl AtomicStats {
+    pub(crate) fn set_recording(&self, record: bool) {
+        self.recording.store(record, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add(&self, counter: Counter, n: u64) {
+        if !self.is_recording() {
+            return;
+        }
+
+        let counter = match counter {
+            Counter::Hits => &self.hits,
+            Counter::Misses => &self.misses,
+            Counter::Inserts => &self.inserts,
+            Counter::Updates => &self.updates,
+            Counter::Removals => &self.removals,
+            Counter::Cleared => &self.cleared,
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_load(&self, succeeded: bool, took: Duration) {
+        if !self.is_recording() {
+            return;
+        }
+
+        if succeeded {
+            self.load_successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.load_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_load_nanos.fetch_add(
+            u64::try_from(took.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Reads every counter. They're read one after another rather than all
+    /// at once, so a snapshot taken while the cache is busy can be a
+    /// little inconsistent. Evictions, expirations and ages aren't counted
+    /// here, and are left for the cache inside to fill in.
+    pub(crate) fn snapshot(&self) -> Option<CacheStats> {
+        if !self.is_recording() {
+            return None;
+        }
+
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        Some(CacheStats {
+            hits: get(&self.hits),
+            misses: get(&self.misses),
+            inserts: get(&self.inserts),
+            updates: get(&self.updates),
+            capacity_evictions: 0,
+            removals: get(&self.removals),
+            cleared: get(&self.cleared),
+            expirations: 0,
+            load_successes: get(&self.load_successes),
+            load_failures: get(&self.load_failures),
+            total_load_time: Duration::from_nanos(get(
+                &self.total_load_nanos
+            )),
+            eviction_ages: AgeHistogram::default(),
+            expiry_ages: AgeHistogram::default(),
+            hit_ages: AgeHistogram::default(),
+        })
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.hits,
+            &self.misses,
+            &self.inserts,
+            &self.updates,
+            &self.removals,
+            &self.cleared,
+            &self.load_successes,
+            &self.load_failures,
+            &self.total_load_nanos,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AgeHistogram, CacheStats};
+
+    #[test]
+    fn test_derived_stats() {
+        let stats = CacheStats {
+            hits: 3,
+            misses: 1,
+            capacity_evictions: 2,
+            expirations: 1,
+            load_successes: 1,
+            load_failures: 1,
+            total_load_time: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        assert_eq!(4, stats.requests());
+        assert_eq!(0.75, stats.hit_rate());
+        assert_eq!(3, stats.evictions());
+        assert_eq!(
+            Some(Duration::from_millis(5)),
+            stats.average_load_time()
+        );
+        assert_eq!(0.0, CacheStats::default().hit_rate());
+        assert_eq!(None, CacheStats::default().average_load_time());
+    }
+
+    #[test]
+    fn test_age_histogram() {
+        let mut ages = AgeHistogram::default();
+        assert_eq!(None, ages.quantile(0.5));
+
+        for millis in [0, 1, 3, 3, 700] {
+            ages.record(Duration::from_millis(millis));
+        }
+        ages.record(Duration::MAX);
+
+        assert_eq!(6, ages.count());
+        let buckets: Vec<_> =
+            ages.buckets().filter(|(_, n)| *n > 0).collect();
+        assert_eq!(
+            vec![
+                (Duration::from_millis(1), 1),
+                (Duration::from_millis(2), 1),
+                (Duration::from_millis(4), 2),
+                (Duration::from_millis(1024), 1),
+                (Duration::MAX, 1),
+            ],
+            buckets
+        );
+
+        assert_eq!(Some(Duration::from_millis(1)), ages.quantile(0.0));
+        assert_eq!(Some(Duration::from_millis(4)), ages.quantile(0.5));
+        assert_eq!(
+            Some(Duration::from_millis(1024)),
+            ages.quantile(0.8)
+        );
+        assert_eq!(Some(Duration::MAX), ages.quantile(1.0));
+    }
+}
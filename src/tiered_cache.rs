@@ -0,0 +1,591 @@
+use parking_lot::Mutex;
+use std::{
+    any::Any,
+    borrow::Borrow,
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::Instant,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{CacheMetrics, MeteredCache};
+use crate::{
+    cache::{Cache, ConcurrentCache},
+    events::{CacheEvent, CacheEvents, EventSource, Overflow},
+    metadata::EntryInfo,
+    KeyHash, LruCache, SharedCache,
+};
+
+/// How many generation counters keys are spread over. Any change to a key
+/// in the back invalidates every front-tier entry which shares its
+/// counter, so more counters means fewer entries thrown out for nothing.
+const GENERATIONS: usize = 256;
+
+/// How many of the back's events can wait to be looked at. They are looked
+/// at after every call which reaches the back, so this only fills up when
+/// a lot of threads are doing that at once; if it overflows, everything in
+/// front is thrown out.
+const EVENT_BUFFER: usize = 1024;
+
+/// How many TieredCaches have been dropped so far, so threads can tell
+/// when there are fronts to sweep out without looking at each of them.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by a TieredCache for as long as it is around, and weakly by every
+/// front tier for it.
+struct Alive;
+
+impl Drop for Alive {
+    // by the time this runs there are no strong handles left, so any
+    // thread which sees the count move on will find the fronts dead
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// This thread's front tier for one TieredCache, along with a way of
+/// telling whether that cache is still around.
+struct Front {
+    alive: Weak<Alive>,
+    cache: Box<dyn Any>,
+}
+
+/// The front tier of every TieredCache this thread has used.
+#[derive(Default)]
+struct Fronts {
+    /// By where the cache's [`Alive`] handle lives, which can't be reused
+    /// for another cache while the front's weak handle holds on to it.
+    by_cache: HashMap<*const Alive, Front>,
+    /// How many caches had been dropped when this was last swept.
+    swept: usize,
+}
+
+impl Fronts {
+    /// Lets go of the fronts for caches which are gone, if any have gone
+    /// since the last time.
+    fn sweep(&mut self) {
+        let dropped = DROPPED.load(Ordering::Acquire);
+        if dropped != self.swept {
+            self.by_cache
+                .retain(|_, front| front.alive.strong_count() > 0);
+            self.swept = dropped;
+        }
+    }
+}
+
+thread_local! {
+    static FRONTS: RefCell<Fronts> = RefCell::new(Fronts::default());
+}
+
+/// A value in a front tier, along with the generation of its key when it
+/// was fetched from the back, and when it expires there.
+struct Fronted<V> {
+    value: V,
+    slot: usize,
+    generation: u64,
+    expires_at: Option<Instant>,
+}
+
+/// The back's events, along with how many of them had been dropped the
+/// last time they were looked at.
+struct Watch {
+    events: CacheEvents,
+    dropped: u64,
+}
+
+struct Tiers<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    back: SharedCache<C>,
+    watch: Mutex<Watch>,
+    generations: Box<[AtomicU64]>,
+    hash_builder: RandomState,
+    front_capacity: usize,
+    alive: Arc<Alive>,
+}
+
+/// A small LruCache for each thread in front of a [`SharedCache`] which
+/// they all share, so the hottest keys are served without taking any lock
+/// at all.
+///
+/// The TieredCache subscribes to the back's events, and anything which
+/// happens to a key there, whether it is written, removed, evicted or
+/// found to have expired, moves the key's generation on. Every thread
+/// checks that before trusting what it has in front, along with when the
+/// item expires in the back, so no thread carries on serving an item the
+/// back no longer has.
+pub struct TieredCache<C>(Arc<Tiers<C>>)
+where
+    C: Cache,
+    C::Value: Clone;
+
+impl<C> TieredCache<C>
+where
+    C: Cache + EventSource,
+    C::Key: 'static,
+    C::Value: Clone + 'static,
+{
+    /// Puts a per-thread front tier holding up to `front_capacity` items in
+    /// front of `cache`.
+    pub fn with_cache_and_front_capacity(
+        cache: C,
+        front_capacity: usize,
+    ) -> Self {
+        let back = SharedCache::with_cache(cache);
+        let events = back.subscribe(EVENT_BUFFER, Overflow::DropOldest);
+
+        Self(Arc::new(Tiers {
+            back,
+            watch: Mutex::new(Watch { events, dropped: 0 }),
+            generations: (0..GENERATIONS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            hash_builder: RandomState::new(),
+            front_capacity,
+            alive: Arc::new(Alive),
+        }))
+    }
+
+    /// Inserts an item into the back tier, so every thread sees it the
+    /// next time it asks.
+    pub fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        let old_v = self.0.back.insert(k, v);
+        self.catch_up();
+        old_v
+    }
+
+    /// Get an item from this thread's front tier if it is there and still
+    /// current, otherwise from the back, keeping it in front for next time.
+    pub fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (hash_k, hit) = self.with_front(|front| {
+            let hash_k = front.hash_k(k);
+
+            let hit = match front.get_mut_hashed(hash_k) {
+                Some(f) if self.is_current(f) => Some(f.value.clone()),
+                Some(_) => {
+                    front.remove_hashed(hash_k);
+                    None
+                }
+                None => None,
+            };
+
+            (hash_k, hit)
+        });
+
+        if hit.is_some() {
+            return hit;
+        }
+
+        // the generation is read before the back is, so if anything
+        // happens to the key in between then what goes in front is
+        // already stale and will be thrown out next time, rather than
+        // served
+        let slot = self.slot(self.0.back.key_hash(k));
+        let generation =
+            self.0.generations[slot].load(Ordering::Acquire);
+        let value = self.0.back.get(k);
+        let expires_at = match value {
+            Some(_) => self
+                .0
+                .back
+                .entry_info(k)
+                .and_then(|info| info.time_to_live)
+                .map(|ttl| Instant::now() + ttl),
+            None => None,
+        };
+        self.catch_up();
+
+        let value = value?;
+        self.with_front(|front| {
+            front.insert_hashed(
+                hash_k,
+                Fronted {
+                    value: value.clone(),
+                    slot,
+                    generation,
+                    expires_at,
+                },
+            )
+        });
+
+        Some(value)
+    }
+
+    /// Looks up what the cache knows about an item without it counting as
+    /// a get, or None if there is no such item. Gets which were served
+    /// from a thread's front tier don't show up in it.
+    pub fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0.back.entry_info(k)
+    }
+
+    /// Remove an item from the cache, returning the removed item if it existed.
+    pub fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = self.0.back.remove(k);
+        self.catch_up();
+        v
+    }
+
+    /// Clears the cache, front tiers included.
+    pub fn clear(&self) {
+        self.0.back.clear();
+        self.catch_up();
+    }
+
+    /// The number of elements in the back tier at present.
+    pub fn len(&self) -> usize {
+        self.0.back.len()
+    }
+
+    /// Whether the back tier is empty at present.
+    pub fn is_empty(&self) -> bool {
+        self.0.back.is_empty()
+    }
+
+    fn slot(&self, hash_k: KeyHash) -> usize {
+        (hash_k % GENERATIONS as u64) as usize
+    }
+
+    fn is_current(&self, fronted: &Fronted<C::Value>) -> bool {
+        let generation =
+            self.0.generations[fronted.slot].load(Ordering::Acquire);
+        fronted.generation == generation
+            && fronted.expires_at.is_none_or(|at| Instant::now() < at)
+    }
+
+    /// Moves on the generation of every key the back has done anything
+    /// to since the last time. This is done under a lock, so by the time
+    /// it returns everything which happened in the back before it was
+    /// called has been seen to, by this thread or another.
+    fn catch_up(&self) {
+        let mut watch = self.0.watch.lock();
+
+        while let Some(event) = watch.events.try_recv() {
+            match event {
+                CacheEvent::Inserted(hash_k)
+                | CacheEvent::Updated(hash_k)
+                | CacheEvent::Evicted(hash_k)
+                | CacheEvent::Expired(hash_k)
+                | CacheEvent::Removed(hash_k) => {
+                    self.0.generations[self.slot(hash_k)]
+                        .fetch_add(1, Ordering::Release);
+                }
+                CacheEvent::Cleared => self.invalidate_all(),
+                CacheEvent::Accessed(_) => {}
+            }
+        }
+
+        // checked after the events were, so whatever was dropped is
+        // covered by throwing everything out
+        let dropped = watch.events.dropped();
+        if dropped != watch.dropped {
+            watch.dropped = dropped;
+            self.invalidate_all();
+        }
+    }
+
+    fn invalidate_all(&self) {
+        for generation in self.0.generations.iter() {
+            generation.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Runs `f` with this thread's front tier, making one if this is the
+    /// first time the thread has used this cache.
+    fn with_front<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut LruCache<C::Key, Fronted<C::Value>>) -> R,
+    {
+        FRONTS.with(|fronts| {
+            let mut fronts = fronts.borrow_mut();
+            fronts.sweep();
+
+            let front = fronts
+                .by_cache
+                .entry(Arc::as_ptr(&self.0.alive))
+                .or_insert_with(|| {
+                    let cache: LruCache<C::Key, Fronted<C::Value>> =
+                        LruCache::with_capacity_and_hash_builder(
+                            self.0.front_capacity,
+                            self.0.hash_builder.clone(),
+                        );
+                    Front {
+                        alive: Arc::downgrade(&self.0.alive),
+                        cache: Box::new(cache),
+                    }
+                })
+                .cache
+                .downcast_mut::<LruCache<C::Key, Fronted<C::Value>>>()
+                .expect("a TieredCache's handle is only used by it");
+
+            f(front)
+        })
+    }
+}
+
+impl<C> ConcurrentCache for TieredCache<C>
+where
+    C: Cache + EventSource,
+    C::Key: 'static,
+    C::Value: Clone + 'static,
+{
+    type Key = C::Key;
+    type Value = C::Value;
+
+    fn insert(&self, k: C::Key, v: C::Value) -> Option<C::Value> {
+        TieredCache::insert(self, k, v)
+    }
+
+    fn get<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        TieredCache::get(self, k)
+    }
+
+    fn entry_info<Q>(&self, k: &Q) -> Option<EntryInfo>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        TieredCache::entry_info(self, k)
+    }
+
+    fn remove<Q>(&self, k: &Q) -> Option<C::Value>
+    where
+        C::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        TieredCache::remove(self, k)
+    }
+
+    fn clear(&self) {
+        TieredCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        TieredCache::len(self)
+    }
+}
+
+/// The metrics of the back tier. Gets served from a thread's front tier
+/// never reach it, so they aren't among its hits.
+#[cfg(feature = "metrics")]
+impl<C> MeteredCache for TieredCache<C>
+where
+    C: Cache + MeteredCache,
+    C::Value: Clone,
+{
+    fn metrics(&self) -> CacheMetrics {
+        self.0.back.metrics()
+    }
+}
+
+impl<C> Clone for TieredCache<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    fn clone(&self) -> Self {
+        TieredCache(self.0.clone())
+    }
+}
+
+/// Shows the back tier. What each thread has in front is only visible to
+/// that thread, and is a copy of what's in the back anyway.
+impl<C> fmt::Debug for TieredCache<C>
+where
+    C: Cache + fmt::Debug,
+    C::Value: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TieredCache")
+            .field("front_capacity", &self.0.front_capacity)
+            .field("back", &self.0.back)
+            .finish()
+    }
+}
+
+impl<C> Drop for Tiers<C>
+where
+    C: Cache,
+    C::Value: Clone,
+{
+    // other threads' fronts can only be dropped by those threads, which
+    // sweep them out the next time they use any TieredCache; this
+    // thread's can go right away
+    fn drop(&mut self) {
+        let _ = FRONTS.try_with(|fronts| {
+            if let Ok(mut fronts) = fronts.try_borrow_mut() {
+                fronts.by_cache.remove(&Arc::as_ptr(&self.alive));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::FRONTS;
+    use crate::{ExpiringCache, LruCache, TieredCache};
+
+    #[test]
+    fn test_tiered_cache() {
+        let cache: TieredCache<LruCache<usize, usize>> =
+            TieredCache::with_cache_and_front_capacity(
+                LruCache::with_capacity(10),
+                2,
+            );
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        // fill another thread's front, then change things under it
+        let (get_tx, get_rx) = mpsc::channel::<usize>();
+        let (got_tx, got_rx) = mpsc::channel();
+        let thread_cache = cache.clone();
+        let reader = thread::spawn(move || {
+            for k in get_rx {
+                got_tx.send(thread_cache.get(&k)).unwrap();
+            }
+        });
+        let get = |k| {
+            get_tx.send(k).unwrap();
+            got_rx.recv().unwrap()
+        };
+
+        assert_eq!(Some(1), get(1));
+        assert_eq!(Some(2), get(2));
+        cache.remove(&1);
+        cache.insert(2, 4);
+        assert_eq!(None, get(1));
+        assert_eq!(Some(4), get(2));
+
+        cache.clear();
+        assert_eq!(None, get(2));
+
+        drop(get_tx);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_fronts_are_dropped() {
+        let cache: TieredCache<LruCache<usize, usize>> =
+            TieredCache::with_cache_and_front_capacity(
+                LruCache::with_capacity(10),
+                2,
+            );
+        let handle = std::sync::Arc::as_ptr(&cache.0.alive);
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+        assert!(FRONTS.with(|fronts| fronts
+            .borrow()
+            .by_cache
+            .contains_key(&handle)));
+
+        drop(cache);
+        assert!(!FRONTS.with(|fronts| fronts
+            .borrow()
+            .by_cache
+            .contains_key(&handle)));
+    }
+
+    #[test]
+    fn test_other_threads_fronts_are_swept() {
+        let make = || -> TieredCache<LruCache<usize, usize>> {
+            TieredCache::with_cache_and_front_capacity(
+                LruCache::with_capacity(10),
+                2,
+            )
+        };
+        let cache = make();
+        cache.insert(1, 1);
+
+        let (used_tx, used_rx) = mpsc::channel();
+        let (dropped_tx, dropped_rx) = mpsc::channel::<()>();
+        let thread_cache = cache.clone();
+        let user = thread::spawn(move || {
+            assert_eq!(Some(1), thread_cache.get(&1));
+            drop(thread_cache);
+            used_tx.send(()).unwrap();
+
+            dropped_rx.recv().unwrap();
+            let other = make();
+            other.get(&1);
+            FRONTS.with(|fronts| fronts.borrow().by_cache.len())
+        });
+
+        used_rx.recv().unwrap();
+        drop(cache);
+        dropped_tx.send(()).unwrap();
+        assert_eq!(1, user.join().unwrap());
+    }
+
+    #[test]
+    fn test_debug() {
+        let cache: TieredCache<LruCache<usize, &str>> =
+            TieredCache::with_cache_and_front_capacity(
+                LruCache::with_capacity(2),
+                1,
+            );
+        cache.insert(1, "one");
+
+        let debug = format!("{:?}", cache);
+        assert!(debug.starts_with(
+            "TieredCache { front_capacity: 1, back: SharedCache {"
+        ));
+        assert!(debug.contains("\"one\""));
+    }
+
+    #[test]
+    fn test_back_evictions() {
+        let cache: TieredCache<LruCache<usize, usize>> =
+            TieredCache::with_cache_and_front_capacity(
+                LruCache::with_capacity(1),
+                2,
+            );
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+
+        cache.insert(2, 2);
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(Some(2), cache.get(&2));
+    }
+
+    #[test]
+    fn test_back_expiry() {
+        let cache: TieredCache<ExpiringCache<usize, usize>> =
+            TieredCache::with_cache_and_front_capacity(
+                ExpiringCache::with_capacity_and_timeout(
+                    10,
+                    Duration::from_millis(20),
+                ),
+                2,
+            );
+        cache.insert(1, 1);
+        assert_eq!(Some(1), cache.get(&1));
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(None, cache.get(&1));
+    }
+}
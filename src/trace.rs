@@ -0,0 +1,362 @@
+//! Reports what a cache is doing through [`tracing`], so that it shows up
+//! alongside everything else in an application's structured logs.
+//!
+//! Every event and span carries the name the cache was given in a `cache`
+//! field, and items are named by the hash of their key in a `key_hash`
+//! field, since most caches don't hold on to their keys.
+
+use std::{sync::Arc, time::Duration};
+
+use tracing::{Level, Span};
+
+use crate::{cache::KeyHash, linked_map::DebugHash};
+
+/// Emits an event at a level which is only known at runtime, since the
+/// macros in [`tracing`] want one they can see at compile time.
+macro_rules! event_at {
+    ($level:expr, $($field:tt)+) => {
+        match $level {
+            Level::ERROR => tracing::event!(Level::ERROR, $($field)+),
+            Level::WARN => tracing::event!(Level::WARN, $($field)+),
+            Level::INFO => tracing::event!(Level::INFO, $($field)+),
+            Level::DEBUG => tracing::event!(Level::DEBUG, $($field)+),
+            Level::TRACE => tracing::event!(Level::TRACE, $($field)+),
+        }
+    };
+}
+
+/// Makes a span at a level which is only known at runtime, like
+/// `event_at!`.
+macro_rules! span_at {
+    ($level:expr, $($field:tt)+) => {
+        match $level {
+            Level::ERROR => tracing::span!(Level::ERROR, $($field)+),
+            Level::WARN => tracing::span!(Level::WARN, $($field)+),
+            Level::INFO => tracing::span!(Level::INFO, $($field)+),
+            Level::DEBUG => tracing::span!(Level::DEBUG, $($field)+),
+            Level::TRACE => tracing::span!(Level::TRACE, $($field)+),
+        }
+    };
+}
+
+/// The level each kind of event is emitted at. The defaults keep the
+/// things which happen on every lookup down at `TRACE`, and the rest at
+/// `DEBUG`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceLevels {
+    /// A lookup which didn't find anything.
+    pub miss: Level,
+    /// A value being loaded, or reloaded, and how that went. Loads also
+    /// get a span at this level, which the loader's own events fall in.
+    pub load: Level,
+    /// An item thrown out to make room for another, along with why.
+    pub eviction: Level,
+    /// An item found to have expired and thrown out.
+    pub expiry: Level,
+    /// How long a [`SharedCache`] waited for its lock.
+    ///
+    /// [`SharedCache`]: crate::SharedCache
+    pub lock_wait: Level,
+}
+
+impl Default for TraceLevels {
+    fn default() -> Self {
+        TraceLevels {
+            miss: Level::TRACE,
+            load: Level::DEBUG,
+            eviction: Level::DEBUG,
+            expiry: Level::DEBUG,
+            lock_wait: Level::TRACE,
+        }
+    }
+}
+
+/// Why an item was evicted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EvictionCause {
+    /// The cache was full, and it was the oldest item.
+    Capacity,
+    /// The cache was full, and it was the oldest item, which had expired
+    /// anyway.
+    Expired,
+}
+
+impl EvictionCause {
+    fn as_str(self) -> &'static str {
+        match self {
+            EvictionCause::Capacity => "capacity",
+            EvictionCause::Expired => "expired",
+        }
+    }
+}
+
+/// Emits [`tracing`] events for a cache under a name. Clones share the
+/// name, so the same tracer can be attached to several caches which
+/// should show up as one, such as the shards of a cache.
+#[derive(Debug, Clone)]
+pub struct CacheTracer {
+    name: Arc<str>,
+    levels: TraceLevels,
+}
+
+impl CacheTracer {
+    /// Makes a tracer which emits events at the default levels.
+    pub fn new(name: impl Into<Arc<str>>) -> Self {
+        CacheTracer::with_levels(name, TraceLevels::default())
+    }
+
+    /// Makes a tracer which emits events at the given levels.
+    pub fn with_levels(
+        name: impl Into<Arc<str>>,
+        levels: TraceLevels,
+    ) -> Self {
+        CacheTracer {
+            name: name.into(),
+            levels,
+        }
+    }
+
+    /// The name of the cache, as it appears in the `cache` field.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The levels events are emitted at.
+    pub fn levels(&self) -> TraceLevels {
+        self.levels
+    }
+
+    pub(crate) fn miss(&self, hash_k: KeyHash) {
+        event_at!(
+            self.levels.miss,
+            cache = &*self.name,
+            key_hash = ?DebugHash(hash_k),
+            "cache miss"
+        );
+    }
+
+    pub(crate) fn evicted(
+        &self,
+        hash_k: KeyHash,
+        cause: EvictionCause,
+    ) {
+        event_at!(
+            self.levels.eviction,
+            cache = &*self.name,
+            key_hash = ?DebugHash(hash_k),
+            cause = cause.as_str(),
+            "cache eviction"
+        );
+    }
+
+    pub(crate) fn expired(&self, hash_k: KeyHash) {
+        event_at!(
+            self.levels.expiry,
+            cache = &*self.name,
+            key_hash = ?DebugHash(hash_k),
+            "cache expiry"
+        );
+    }
+
+    /// A span to run a load in, for the key with the given hash if it is
+    /// known.
+    pub(crate) fn load_span(&self, hash_k: Option<KeyHash>) -> Span {
+        span_at!(
+            self.levels.load,
+            "cache_load",
+            cache = &*self.name,
+            key_hash = hash_k.map(|hash_k| {
+                tracing::field::debug(DebugHash(hash_k))
+            }),
+        )
+    }
+
+    /// Notes how a load went, from within its span.
+    pub(crate) fn loaded(&self, ok: bool, took: Duration) {
+        event_at!(
+            self.levels.load,
+            cache = &*self.name,
+            ok,
+            took_us = took.as_micros() as u64,
+            "cache load"
+        );
+    }
+
+    /// Notes how long it took to take the lock, for reading or writing.
+    #[cfg(feature = "shared_cache")]
+    pub(crate) fn lock_waited(&self, write: bool, waited: Duration) {
+        event_at!(
+            self.levels.lock_wait,
+            cache = &*self.name,
+            write,
+            waited_us = waited.as_micros() as u64,
+            "cache lock wait"
+        );
+    }
+}
+
+/// A cache which can report what it does through a [`CacheTracer`].
+pub trait Traceable {
+    /// Starts emitting events through the tracer, in place of any tracer
+    /// which was attached before.
+    fn attach_tracer(&mut self, tracer: CacheTracer);
+
+    /// Stops emitting events, handing back the tracer.
+    fn detach_tracer(&mut self) -> Option<CacheTracer>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Level, Metadata, Subscriber,
+    };
+
+    use super::{CacheTracer, TraceLevels, Traceable};
+    use crate::{Cache, ExpiringCache, LruCache};
+
+    /// Writes down the fields of every event, one string per event.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(
+            &mut self,
+            field: &Field,
+            value: &dyn fmt::Debug,
+        ) {
+            self.0 += &format!("{}={:?} ", field.name(), value);
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields =
+                Fields(format!("level={} ", event.metadata().level()));
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    impl Recorder {
+        fn events(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn test_misses_and_evictions() {
+        let recorder = Recorder::default();
+        let mut cache: LruCache<u64, u64> = LruCache::with_capacity(1);
+        cache.attach_tracer(CacheTracer::with_levels(
+            "users",
+            TraceLevels {
+                eviction: Level::WARN,
+                ..TraceLevels::default()
+            },
+        ));
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            cache.get(&1);
+            cache.insert(1, 1);
+            cache.insert(2, 2);
+            cache.get(&2);
+        });
+
+        let events = recorder.events();
+        assert_eq!(2, events.len());
+        assert!(events[0].starts_with("level=TRACE message=cache miss"));
+        assert!(events[0].contains("cache=\"users\""));
+        assert!(
+            events[1].starts_with("level=WARN message=cache eviction")
+        );
+        assert!(events[1].contains(&format!(
+            "key_hash={:#018x}",
+            cache.key_hash(&1)
+        )));
+        assert!(events[1].contains("cause=\"capacity\""));
+
+        assert!(cache.detach_tracer().is_some());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let recorder = Recorder::default();
+        let mut cache: ExpiringCache<u64, u64> =
+            ExpiringCache::with_capacity_and_timeout(
+                1,
+                Duration::from_millis(10),
+            );
+        cache.attach_tracer(CacheTracer::new("sessions"));
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            cache.insert(1, 1);
+            std::thread::sleep(Duration::from_millis(20));
+            cache.insert(2, 2);
+            std::thread::sleep(Duration::from_millis(20));
+            cache.get(&2);
+        });
+
+        let events = recorder.events();
+        assert_eq!(3, events.len());
+        assert!(events[0].contains("cause=\"expired\""));
+        assert!(
+            events[1].starts_with("level=DEBUG message=cache expiry")
+        );
+        assert!(events[2].starts_with("level=TRACE message=cache miss"));
+    }
+
+    #[cfg(feature = "shared_cache")]
+    #[test]
+    fn test_loads_and_lock_waits() {
+        use crate::SharedCache;
+
+        let recorder = Recorder::default();
+        let cache: SharedCache<LruCache<u64, u64>> =
+            SharedCache::with_cache(LruCache::with_capacity(10));
+        cache.attach_tracer(CacheTracer::new("shared"));
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            cache.get_or_insert_with(1, || 1);
+        });
+
+        let events = recorder.events();
+        assert!(events
+            .iter()
+            .any(|e| e.contains("message=cache miss")));
+        assert!(events.iter().any(|e| {
+            e.contains("message=cache load") && e.contains("ok=true")
+        }));
+        assert!(events.iter().any(|e| {
+            e.contains("message=cache lock wait")
+                && e.contains("write=true")
+        }));
+        assert!(events.iter().all(|e| e.contains("cache=\"shared\"")));
+
+        assert!(cache.detach_tracer().is_some());
+    }
+}